@@ -0,0 +1,243 @@
+use std::{collections::BTreeMap, error::Error, path::Path, time::Duration};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// A bout between the bots assigned to an arena
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Match {
+    pub number: usize,
+    pub bots: Vec<String>,
+    pub duration: Option<Duration>,
+    pub started_at: Option<DateTime<Local>>,
+    pub ended_at: Option<DateTime<Local>>,
+    pub scores: BTreeMap<String, i64>,
+}
+
+impl Match {
+    pub fn new(number: usize, bots: Vec<String>, duration: Option<Duration>) -> Self {
+        let scores = bots.iter().map(|bot| (bot.clone(), 0)).collect();
+        Self {
+            number,
+            bots,
+            duration,
+            started_at: None,
+            ended_at: None,
+            scores,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.started_at.is_some() && self.ended_at.is_none()
+    }
+
+    pub fn has_bot(&self, name: &str) -> bool {
+        self.bots.iter().any(|bot| bot == name)
+    }
+
+    /// When the match timer runs out, if the match has a time limit and is running
+    pub fn deadline(&self) -> Option<DateTime<Local>> {
+        match (self.started_at, self.duration, self.ended_at) {
+            (Some(started_at), Some(duration), None) => {
+                Some(started_at + chrono::Duration::from_std(duration).unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn elapsed(&self, now: DateTime<Local>) -> Option<Duration> {
+        self.started_at.map(|started_at| {
+            (self.ended_at.unwrap_or(now) - started_at)
+                .to_std()
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn describe(&self, now: DateTime<Local>) -> String {
+        let state = match (self.elapsed(now), self.ended_at) {
+            (None, _) => "waiting".to_string(),
+            (Some(elapsed), None) => format!("running for {}s", elapsed.as_secs()),
+            (Some(elapsed), Some(_)) => format!("ended after {}s", elapsed.as_secs()),
+        };
+        let limit = self
+            .duration
+            .map(|d| format!(" ({}s limit)", d.as_secs()))
+            .unwrap_or_default();
+        let scores: Vec<String> = self
+            .scores
+            .iter()
+            .map(|(bot, score)| format!("{} {}", bot, score))
+            .collect();
+        format!(
+            "match {}: {}, {}{}, score {}",
+            self.number,
+            self.bots.join(" vs "),
+            state,
+            limit,
+            scores.join(" ")
+        )
+    }
+}
+
+/// A ring where one match at a time is played, run by the referees attached to it
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Arena {
+    pub name: String,
+    pub current: Option<Match>,
+    pub played: Vec<Match>,
+}
+
+impl Arena {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            current: None,
+            played: Vec::new(),
+        }
+    }
+
+    /// Replaces the current match with a new one, archiving it if it was played
+    pub fn next_match(&mut self, bots: Vec<String>, duration: Option<Duration>) -> &Match {
+        if let Some(current) = self.current.take() {
+            if current.started_at.is_some() {
+                self.played.push(current);
+            }
+        }
+        let number = self.played.len() + 1;
+        self.current.insert(Match::new(number, bots, duration))
+    }
+
+    /// Total points of each bot over every match played in this arena, best first
+    pub fn standings(&self) -> Vec<(String, i64)> {
+        let mut totals = BTreeMap::new();
+        for m in self.played.iter().chain(self.current.iter()) {
+            for (bot, score) in m.scores.iter() {
+                *totals.entry(bot.clone()).or_insert(0) += score;
+            }
+        }
+        let mut standings: Vec<(String, i64)> = totals.into_iter().collect();
+        standings.sort_by(|(_, a), (_, b)| b.cmp(a));
+        standings
+    }
+}
+
+/// The arenas and their matches, saved to the broker state file after every change
+#[derive(Serialize, Deserialize, Default)]
+pub struct TournamentState {
+    pub arenas: BTreeMap<String, Arena>,
+}
+
+impl TournamentState {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let text = serde_json::to_string_pretty(self)?;
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, text)?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// The arena whose current match includes this bot
+    pub fn bot_arena(&self, bot: &str) -> Option<&Arena> {
+        self.arenas.values().find(|arena| {
+            arena
+                .current
+                .as_ref()
+                .map(|m| m.has_bot(bot))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bots(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn time(text: &str) -> DateTime<Local> {
+        DateTime::parse_from_rfc3339(text).unwrap().into()
+    }
+
+    #[test]
+    fn next_match_archives_only_played_matches() {
+        let mut arena = Arena::new("north".to_string());
+        arena.next_match(bots(&["frog", "toad"]), None);
+        // Never started, so replaced rather than archived
+        assert_eq!(arena.next_match(bots(&["frog", "newt"]), None).number, 1);
+        assert!(arena.played.is_empty());
+
+        arena.current.as_mut().unwrap().started_at = Some(Local::now());
+        assert_eq!(arena.next_match(bots(&["toad", "newt"]), None).number, 2);
+        assert_eq!(arena.played.len(), 1);
+        assert_eq!(arena.played[0].bots, bots(&["frog", "newt"]));
+    }
+
+    #[test]
+    fn standings_add_up_every_match_best_first() {
+        let mut arena = Arena::new("north".to_string());
+        let m = arena.next_match(bots(&["frog", "toad"]), None).clone();
+        arena.current = Some(Match {
+            started_at: Some(Local::now()),
+            scores: [("frog".to_string(), 1), ("toad".to_string(), 3)].into(),
+            ..m
+        });
+        arena.next_match(bots(&["frog", "newt"]), None);
+        let current = arena.current.as_mut().unwrap();
+        current.scores.insert("frog".to_string(), 4);
+
+        assert_eq!(
+            arena.standings(),
+            vec![
+                ("frog".to_string(), 5),
+                ("toad".to_string(), 3),
+                ("newt".to_string(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn match_timer_and_description() {
+        let mut m = Match::new(2, bots(&["frog", "toad"]), Some(Duration::from_secs(60)));
+        let now = time("2024-05-01T14:00:30+00:00");
+        assert_eq!(m.deadline(), None);
+        assert_eq!(
+            m.describe(now),
+            "match 2: frog vs toad, waiting (60s limit), score frog 0 toad 0"
+        );
+
+        m.started_at = Some(time("2024-05-01T14:00:00+00:00"));
+        m.scores.insert("toad".to_string(), 1);
+        assert_eq!(m.deadline(), Some(time("2024-05-01T14:01:00+00:00")));
+        assert_eq!(
+            m.describe(now),
+            "match 2: frog vs toad, running for 30s (60s limit), score frog 0 toad 1"
+        );
+
+        m.ended_at = Some(time("2024-05-01T14:00:45+00:00"));
+        assert!(!m.is_running());
+        assert_eq!(m.deadline(), None);
+        assert_eq!(m.elapsed(now), Some(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn bots_are_found_in_their_current_arena() {
+        let mut state = TournamentState::default();
+        for (name, pair) in [("north", ["frog", "toad"]), ("south", ["newt", "eft"])] {
+            let mut arena = Arena::new(name.to_string());
+            arena.next_match(bots(&pair), None);
+            state.arenas.insert(name.to_string(), arena);
+        }
+        assert_eq!(
+            state.bot_arena("eft").map(|a| a.name.as_str()),
+            Some("south")
+        );
+        assert!(state.bot_arena("salamander").is_none());
+    }
+}
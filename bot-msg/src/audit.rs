@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::TIME_FORMAT;
+
+/// The privileged actions that end up in the audit log
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum AuditAction {
+    RefereeClaim,
+    RefereeCommand,
+    ArenaClaim,
+    Match,
+    Score,
+    ParamSet,
+    Kick,
+    Rename,
+    EmergencyStop,
+}
+
+impl AuditAction {
+    pub const ALL: [AuditAction; 9] = [
+        AuditAction::RefereeClaim,
+        AuditAction::RefereeCommand,
+        AuditAction::ArenaClaim,
+        AuditAction::Match,
+        AuditAction::Score,
+        AuditAction::ParamSet,
+        AuditAction::Kick,
+        AuditAction::Rename,
+        AuditAction::EmergencyStop,
+    ];
+
+    pub fn encode(&self) -> &'static str {
+        match self {
+            AuditAction::RefereeClaim => "referee_claim",
+            AuditAction::RefereeCommand => "referee_command",
+            AuditAction::ArenaClaim => "arena_claim",
+            AuditAction::Match => "match",
+            AuditAction::Score => "score",
+            AuditAction::ParamSet => "param_set",
+            AuditAction::Kick => "kick",
+            AuditAction::Rename => "rename",
+            AuditAction::EmergencyStop => "emergency_stop",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.encode() == text)
+    }
+}
+
+impl std::str::FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s).ok_or_else(|| format!("invalid audit action '{}'", s))
+    }
+}
+
+/// Who did what and when, as stored in the audit log
+///
+/// The client id tells apart clients that share a name, like two referees.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub time: DateTime<Local>,
+    pub action: String,
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub detail: String,
+}
+
+impl AuditEntry {
+    fn actor_info(&self) -> String {
+        let mut info = self.actor.clone();
+        if let Some(address) = &self.address {
+            info.push_str(&format!(" at {}", address));
+        }
+        if let Some(client) = &self.client {
+            info.push_str(&format!(" ({})", client));
+        }
+        info
+    }
+}
+
+/// Appends audit entries to a file, writing each one through at once
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&mut self, entry: &AuditEntry) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Prints who did what from an audit log, optionally only for some actions
+pub fn summarise(path: &Path, actions: &[AuditAction]) -> Result<(), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    let mut invalid_lines = 0;
+    for line in reader.lines() {
+        match serde_json::from_str::<AuditEntry>(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => invalid_lines += 1,
+        }
+    }
+    entries.retain(|entry| {
+        actions.is_empty()
+            || AuditAction::decode(&entry.action)
+                .map(|action| actions.contains(&action))
+                .unwrap_or(false)
+    });
+
+    match (entries.first(), entries.last()) {
+        (Some(first), Some(last)) => println!(
+            "{} entries from {} to {}",
+            entries.len(),
+            first.time.format(TIME_FORMAT),
+            last.time.format(TIME_FORMAT)
+        ),
+        _ => println!("no entries"),
+    }
+    if invalid_lines > 0 {
+        println!("{} invalid lines skipped", invalid_lines);
+    }
+
+    let mut actors: BTreeMap<String, BTreeMap<&str, usize>> = BTreeMap::new();
+    for entry in entries.iter() {
+        *actors
+            .entry(entry.actor_info())
+            .or_default()
+            .entry(&entry.action)
+            .or_default() += 1;
+    }
+    if !actors.is_empty() {
+        println!();
+        println!("actors:");
+    }
+    for (actor, counts) in actors {
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(action, count)| format!("{} {}", action, count))
+            .collect();
+        println!("  {}: {}", actor, counts.join(", "));
+    }
+
+    if !entries.is_empty() {
+        println!();
+        println!("timeline:");
+    }
+    for entry in entries.iter() {
+        println!(
+            "  {} {} {}: {}",
+            entry.time.format(TIME_FORMAT),
+            entry.actor_info(),
+            entry.action,
+            entry.detail
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_actions_round_trip() {
+        for action in AuditAction::ALL {
+            assert_eq!(action.encode().parse::<AuditAction>(), Ok(action));
+        }
+        assert!("reboot".parse::<AuditAction>().is_err());
+    }
+
+    #[test]
+    fn audit_entries_are_appended_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let entry = AuditEntry {
+            time: Local::now(),
+            action: AuditAction::Kick.encode().to_string(),
+            actor: "referee".to_string(),
+            address: Some("10.0.0.5:4000".to_string()),
+            client: None,
+            detail: "bot frog".to_string(),
+        };
+        for _ in 0..2 {
+            AuditLog::open(path.clone())
+                .unwrap()
+                .append(&entry)
+                .unwrap();
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+
+        let entries: Vec<AuditEntry> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].detail, "bot frog");
+        assert_eq!(entries[1].actor_info(), "referee at 10.0.0.5:4000");
+    }
+}
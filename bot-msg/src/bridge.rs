@@ -0,0 +1,310 @@
+//! Presents a bot tethered over USB serial to the broker, as if it were on Wi-Fi
+//!
+//! Lines and telemetry frames read from the serial port go to the broker, and what
+//! the broker sends to the bot goes back over serial. The bridge claims the bot name
+//! and answers the broker pings itself, so the firmware only needs to print its logs.
+
+use std::{error::Error, time::Duration};
+
+use chrono::Local;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    select,
+    sync::mpsc,
+};
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::{
+    read_bot_message, BotMessage, ANNOUNCE_PREFIX, BOT_LINE_PREFIX, RECONNECT_MAX_DELAY,
+    RECONNECT_MIN_DELAY, TELEMETRY_MARKER,
+};
+
+/// Where the bridge reads the bot from, and how it presents it to the broker
+pub struct BridgeOptions {
+    pub port: String,
+    pub baud_rate: u32,
+    pub broker: String,
+    pub name: String,
+    pub team: Option<String>,
+}
+
+impl BridgeOptions {
+    /// Asks for `#` lines, so that the broker gives the bridge a session
+    fn claim(&self) -> String {
+        let mut claim = format!("LINES\nNAME:{}\n", self.name);
+        if let Some(team) = &self.team {
+            claim.push_str(&format!("TEAM:{}\n", team));
+        }
+        claim
+    }
+}
+
+/// Lines that would make the bot claim another identity than the bridge one
+fn is_claim_line(line: &str) -> bool {
+    ["LINES", "NAME:", "RESUME:", "TEAM:"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+/// Forwards the serial lines and telemetry frames to the broker
+async fn serial_to_broker<R: AsyncRead + Unpin>(
+    mut serial_reader: BufReader<R>,
+    to_broker: mpsc::Sender<Vec<u8>>,
+) -> String {
+    loop {
+        let bytes = match read_bot_message(&mut serial_reader).await {
+            Ok(Some(BotMessage::Line(line))) => {
+                if is_claim_line(&line) {
+                    println!("{}: ignoring serial claim '{}'", Local::now(), line);
+                    continue;
+                }
+                format!("{}\n", line).into_bytes()
+            }
+            Ok(Some(BotMessage::Telemetry(payload))) => {
+                let mut frame = vec![TELEMETRY_MARKER];
+                frame.extend((payload.len() as u16).to_le_bytes());
+                frame.extend(payload);
+                frame
+            }
+            Ok(None) => return "serial port closed".to_string(),
+            Err(err) => return format!("error reading the serial port: {}", err),
+        };
+        if to_broker.send(bytes).await.is_err() {
+            return "broker connection closed".to_string();
+        }
+    }
+}
+
+/// Forwards the broker commands to the bot, handling pings and sessions on its behalf
+async fn broker_to_serial<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut broker_reader: BufReader<R>,
+    mut serial_writer: W,
+    to_broker: mpsc::Sender<Vec<u8>>,
+    options: &BridgeOptions,
+    session: &mut Option<String>,
+) -> String {
+    loop {
+        let byte = match broker_reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(_) => return "broker closed the connection".to_string(),
+        };
+        let mut to_serial = vec![byte];
+        match byte {
+            0 => {
+                to_broker.send(b"PONG\n".to_vec()).await.ok();
+                continue;
+            }
+            // Bare newlines carry no command, the bot has nothing to do with them
+            b'\n' => continue,
+            byte if byte == BOT_LINE_PREFIX.as_bytes()[0] => {
+                let mut line = Vec::new();
+                if broker_reader.read_until(b'\n', &mut line).await.is_err() {
+                    return "broker closed the connection".to_string();
+                }
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end();
+                if let Some(token) = text.strip_prefix("SESSION:") {
+                    *session = Some(token.to_string());
+                    continue;
+                }
+                // The firmware may not read `#` lines, only the bridge asked for them
+                if let Some(announcement) = text.strip_prefix(ANNOUNCE_PREFIX) {
+                    println!("{}: announcement: {}", Local::now(), announcement);
+                    continue;
+                }
+                if text == "SESSION_UNKNOWN" {
+                    println!("{}: session expired, claiming the name again", Local::now());
+                    *session = None;
+                    to_broker.send(options.claim().into_bytes()).await.ok();
+                    continue;
+                }
+                to_serial.extend(line);
+            }
+            _ => {}
+        }
+        if let Err(err) = serial_writer.write_all(&to_serial).await {
+            return format!("error writing the serial port: {}", err);
+        }
+    }
+}
+
+async fn write_to_broker<W: AsyncWrite + Unpin>(
+    mut broker_writer: W,
+    mut from_bridge: mpsc::Receiver<Vec<u8>>,
+) -> String {
+    while let Some(bytes) = from_bridge.recv().await {
+        if broker_writer.write_all(&bytes).await.is_err() {
+            return "broker closed the connection".to_string();
+        }
+    }
+    "bridge stopped".to_string()
+}
+
+/// Bridges a serial port and a broker connection until either closes, returning why
+async fn bridge<S, B>(
+    serial: S,
+    broker: B,
+    options: &BridgeOptions,
+    session: &mut Option<String>,
+) -> String
+where
+    S: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (serial_reader, serial_writer) = tokio::io::split(serial);
+    let (broker_reader, broker_writer) = tokio::io::split(broker);
+    let (to_broker, from_bridge) = mpsc::channel(32);
+
+    let claim = match session.as_ref() {
+        Some(token) => format!("LINES\nRESUME:{}\n", token),
+        None => options.claim(),
+    };
+    to_broker.send(claim.into_bytes()).await.ok();
+
+    select! {
+        reason = serial_to_broker(BufReader::new(serial_reader), to_broker.clone()) => reason,
+        reason = broker_to_serial(
+            BufReader::new(broker_reader),
+            serial_writer,
+            to_broker,
+            options,
+            session,
+        ) => reason,
+        reason = write_to_broker(broker_writer, from_bridge) => reason,
+    }
+}
+
+async fn retry(delay: &mut Duration) {
+    tokio::time::sleep(*delay).await;
+    *delay = (*delay * 2).min(RECONNECT_MAX_DELAY);
+}
+
+/// Keeps the bot bridged, reopening the serial port and the broker connection as needed
+pub async fn run(options: BridgeOptions) -> Result<(), Box<dyn Error>> {
+    let mut session = None;
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        let serial = match tokio_serial::new(&options.port, options.baud_rate).open_native_async() {
+            Ok(serial) => serial,
+            Err(err) => {
+                println!(
+                    "{}: cannot open {}: {}, retrying in {}s",
+                    Local::now(),
+                    &options.port,
+                    err,
+                    delay.as_secs_f32()
+                );
+                retry(&mut delay).await;
+                continue;
+            }
+        };
+        let stream = match TcpStream::connect(&options.broker).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!(
+                    "{}: cannot connect to {}: {}, retrying in {}s",
+                    Local::now(),
+                    &options.broker,
+                    err,
+                    delay.as_secs_f32()
+                );
+                retry(&mut delay).await;
+                continue;
+            }
+        };
+        delay = RECONNECT_MIN_DELAY;
+        println!(
+            "{}: bridging {} to {} as bot {}",
+            Local::now(),
+            &options.port,
+            &options.broker,
+            &options.name
+        );
+        let reason = bridge(serial, stream, &options, &mut session).await;
+        println!("{}: bridge interrupted: {}", Local::now(), reason);
+        retry(&mut delay).await;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::{
+        io::{AsyncBufRead, Lines},
+        net::TcpListener,
+        time::timeout,
+    };
+    use tokio_serial::SerialStream;
+
+    use super::*;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn read_serial<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        timeout(TEST_TIMEOUT, reader.read_exact(&mut bytes))
+            .await
+            .expect("serial read timed out")
+            .unwrap();
+        bytes
+    }
+
+    async fn next_line<R: AsyncBufRead + Unpin>(lines: &mut Lines<R>) -> String {
+        timeout(TEST_TIMEOUT, lines.next_line())
+            .await
+            .expect("broker read timed out")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn bridges_a_pseudo_terminal_to_the_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = BridgeOptions {
+            port: "pty".to_string(),
+            baud_rate: 115_200,
+            broker: address.to_string(),
+            name: "frog".to_string(),
+            team: Some("green".to_string()),
+        };
+        let (bot_end, bridge_end) = SerialStream::pair().unwrap();
+        let (mut bot_reader, mut bot_writer) = tokio::io::split(bot_end);
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut session = None;
+
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            assert_eq!(next_line(&mut lines).await, "LINES");
+            assert_eq!(next_line(&mut lines).await, "NAME:frog");
+            assert_eq!(next_line(&mut lines).await, "TEAM:green");
+
+            // The bridge speaks for the bot, which cannot claim another name
+            bot_writer.write_all(b"NAME:toad\nhello\n").await.unwrap();
+            assert_eq!(next_line(&mut lines).await, "hello");
+
+            writer.write_all(&[0]).await.unwrap();
+            assert_eq!(next_line(&mut lines).await, "PONG");
+
+            // Pings, sessions and announcements stay on the bridge
+            writer
+                .write_all(b"\n#SESSION:abc\n#ANNOUNCE:next match\nx#SET:speed=3\n")
+                .await
+                .unwrap();
+            assert_eq!(read_serial(&mut bot_reader, 14).await, b"x#SET:speed=3\n");
+        };
+        let (reason, _) = tokio::join!(
+            async {
+                select! {
+                    reason = bridge(bridge_end, stream, &options, &mut session) => reason,
+                    _ = tokio::time::sleep(TEST_TIMEOUT * 2) => "test timed out".to_string(),
+                }
+            },
+            broker
+        );
+        assert_eq!(reason, "broker closed the connection");
+        assert_eq!(session.as_deref(), Some("abc"));
+    }
+}
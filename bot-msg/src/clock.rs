@@ -0,0 +1,124 @@
+//! Clock synchronisation between the broker and its bots
+//!
+//! A bot sends `SYNC:<bot ms>` with the time of its own clock, and the broker answers
+//! `#SYNC:<bot ms>:<broker ms>` with the milliseconds since the broker started, which
+//! is the broker epoch. From the round trip the bot can convert its clock to broker
+//! time, and stamp its lines with `@<broker ms> ` to tell when it produced them.
+
+use std::collections::VecDeque;
+
+const MAX_SYNC_SAMPLES: usize = 64;
+
+/// How far a bot clock is from the broker clock, sampled at every sync request
+#[derive(Default)]
+pub struct ClockSync {
+    /// Broker time and offset of the bot clock, both in milliseconds
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockSync {
+    /// Adds a sample from a sync request, received half the latency after it was sent
+    pub fn add(&mut self, broker_ms: f64, bot_ms: f64, latency_ms: f64) {
+        let offset = broker_ms - latency_ms / 2.0 - bot_ms;
+        self.samples.push_back((broker_ms, offset));
+        if self.samples.len() > MAX_SYNC_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Least squares fit of the offset over broker time, as slope and intercept
+    fn fit(&self) -> Option<(f64, f64)> {
+        let (first, last) = (self.samples.front()?.0, self.samples.back()?.0);
+        // Over less than a second the jitter of the samples outweighs any drift
+        if last - first < 1000.0 {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let mean_time = self.samples.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_offset = self.samples.iter().map(|(_, o)| o).sum::<f64>() / n;
+        let (covariance, variance) = self.samples.iter().fold((0.0, 0.0), |(c, v), (t, o)| {
+            (
+                c + (t - mean_time) * (o - mean_offset),
+                v + (t - mean_time) * (t - mean_time),
+            )
+        });
+        let slope = covariance / variance;
+        Some((slope, mean_offset - slope * mean_time))
+    }
+
+    /// Milliseconds to add to the bot clock to get broker time
+    pub fn offset_ms(&self) -> Option<f64> {
+        let (latest_time, latest_offset) = *self.samples.back()?;
+        Some(match self.fit() {
+            Some((slope, intercept)) => slope * latest_time + intercept,
+            None => latest_offset,
+        })
+    }
+
+    /// How fast the bot clock runs compared to the broker clock, in parts per million
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.fit().map(|(slope, _)| -slope * 1_000_000.0)
+    }
+
+    pub fn describe(&self) -> String {
+        match (self.offset_ms(), self.drift_ppm()) {
+            (None, _) => "clock not synchronised".to_string(),
+            (Some(offset), None) => format!("clock offset {:+.1}ms", offset),
+            (Some(offset), Some(drift)) => {
+                format!("clock offset {:+.1}ms drift {:+.1}ppm", offset, drift)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn offset_accounts_for_half_the_latency() {
+        let mut clock = ClockSync::default();
+        assert_eq!(clock.describe(), "clock not synchronised");
+        clock.add(10_000.0, 4_000.0, 20.0);
+        assert_close(clock.offset_ms(), 5_990.0);
+        // Samples within a second are too close to tell any drift
+        clock.add(10_500.0, 4_500.0, 20.0);
+        assert_eq!(clock.drift_ppm(), None);
+        assert_eq!(clock.describe(), "clock offset +5990.0ms");
+    }
+
+    #[test]
+    fn drift_is_fitted_over_the_samples() {
+        let mut clock = ClockSync::default();
+        // The bot clock runs 100ppm fast and started 500ms after the broker
+        for second in 0..10 {
+            let broker_ms = second as f64 * 1000.0;
+            clock.add(broker_ms, broker_ms * 1.0001 - 500.0, 0.0);
+        }
+        assert_close(clock.drift_ppm(), 100.0);
+        assert_close(clock.offset_ms(), 500.0 - 9000.0 * 0.0001);
+    }
+
+    #[test]
+    fn old_samples_are_forgotten() {
+        let mut clock = ClockSync::default();
+        for second in 0..MAX_SYNC_SAMPLES + 10 {
+            let broker_ms = second as f64 * 1000.0;
+            // The bot clock jumps after the first samples
+            let jump = if second < 10 { 300.0 } else { 0.0 };
+            clock.add(broker_ms, broker_ms + jump, 0.0);
+        }
+        assert_close(clock.drift_ppm(), 0.0);
+        assert_close(clock.offset_ms(), 0.0);
+    }
+}
@@ -0,0 +1,641 @@
+//! Full screen referee client, built on the same broker connection as `cmd_client`
+//!
+//! The dashboard polls the broker with `BOARD` queries for the bots and the arena,
+//! and keeps the other lines it receives in a scrolling log pane.
+
+use std::{collections::VecDeque, error::Error, time::Duration};
+
+use chrono::{DateTime, Local};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Paragraph},
+    DefaultTerminal, Frame,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{select, spawn, sync::mpsc, time::interval};
+
+use crate::{
+    arena::{Arena, Match},
+    client_connection, ClientEvent, LogLevel, RefereeCommand, ANNOUNCE_PREFIX, ERROR_PREFIX,
+    INFO_END, INFO_PREFIX, LOG_PREFIX,
+};
+
+/// Marks the reply to a `BOARD` query, followed by the board as JSON
+pub const BOARD_PREFIX: &str = "BOARD:";
+
+const BOARD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_LOG_LINES: usize = 2000;
+
+const HELP: &str = "x START  z STOP  up/down select  +/- or 1-9 points  n next match  \
+                    a announce  f filter  pgup/pgdn scroll  q quit";
+
+/// A bot as shown on the dashboard, connected or waiting to resume its session
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BoardBot {
+    pub name: String,
+    pub team: Option<String>,
+    pub latency_ms: Option<f64>,
+    pub last_ack: Option<String>,
+    pub disconnected_at: Option<DateTime<Local>>,
+}
+
+/// What the broker tells a dashboard: the bots and the arena of the referee
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Board {
+    pub time: DateTime<Local>,
+    pub bots: Vec<BoardBot>,
+    pub arena: Option<Arena>,
+}
+
+enum PromptKind {
+    Match,
+    Announce,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    text: String,
+}
+
+struct LogEntry {
+    source: Option<String>,
+    line: Line<'static>,
+}
+
+struct Dashboard {
+    board: Option<Board>,
+    /// How far the broker clock is ahead of ours, to show the match countdown
+    clock_offset: chrono::Duration,
+    is_connected: bool,
+    logs: VecDeque<LogEntry>,
+    selected: usize,
+    filter: Option<String>,
+    scroll: usize,
+    prompt: Option<Prompt>,
+}
+
+/// What follows the time at the start of event lines, as in
+/// `2024-05-01 14:03:00.123 +02:00:frog:...`
+fn strip_event_time(line: &str) -> Option<&str> {
+    let offset = line.find(" +").or_else(|| line.find(" -"))?;
+    line.get(offset + 7..)?.strip_prefix(':')
+}
+
+/// The bot or arena an event line is about
+fn event_source(line: &str) -> Option<&str> {
+    strip_event_time(line)?.split(':').next()
+}
+
+fn level_style(level: LogLevel) -> Style {
+    match level {
+        LogLevel::Error => Style::new().fg(Color::Red),
+        LogLevel::Warn => Style::new().fg(Color::Yellow),
+        LogLevel::Info => Style::new(),
+        LogLevel::Debug => Style::new().fg(Color::Cyan),
+        LogLevel::Trace => Style::new().add_modifier(Modifier::DIM),
+    }
+}
+
+fn format_seconds(seconds: i64) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        Self {
+            board: None,
+            clock_offset: chrono::Duration::zero(),
+            is_connected: false,
+            logs: VecDeque::new(),
+            selected: 0,
+            filter: None,
+            scroll: 0,
+            prompt: None,
+        }
+    }
+
+    fn current_match(&self) -> Option<&Match> {
+        self.board.as_ref()?.arena.as_ref()?.current.as_ref()
+    }
+
+    fn selected_bot(&self) -> Option<&BoardBot> {
+        self.board.as_ref()?.bots.get(self.selected)
+    }
+
+    fn log(&mut self, source: Option<String>, line: Line<'static>) {
+        self.logs.push_back(LogEntry { source, line });
+        if self.logs.len() > MAX_LOG_LINES {
+            self.logs.pop_front();
+        }
+    }
+
+    fn handle_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::Line(line) => {
+                self.is_connected = true;
+                self.handle_line(line);
+            }
+            ClientEvent::Status(status) => {
+                self.is_connected = !status.contains("[disconnected]");
+                self.log(None, Line::styled(status, Style::new().fg(Color::Magenta)));
+            }
+        }
+    }
+
+    fn handle_line(&mut self, line: String) {
+        if line == INFO_END {
+            return;
+        }
+        if let Some(board) = line.strip_prefix(BOARD_PREFIX) {
+            if let Ok(board) = serde_json::from_str::<Board>(board) {
+                self.clock_offset = board.time - Local::now();
+                self.selected = self.selected.min(board.bots.len().saturating_sub(1));
+                self.board = Some(board);
+            }
+            return;
+        }
+        if let Some(info) = line.strip_prefix(INFO_PREFIX) {
+            self.log(None, Line::raw(info.to_string()));
+            return;
+        }
+        if let Some(error) = line.strip_prefix(ERROR_PREFIX) {
+            let error = strip_event_time(error).unwrap_or(error).to_string();
+            self.log(
+                None,
+                Line::styled(
+                    format!("error: {}", error),
+                    Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+                ),
+            );
+            return;
+        }
+        if let Some(announcement) = line.strip_prefix(ANNOUNCE_PREFIX) {
+            self.log(
+                None,
+                Line::styled(
+                    format!("*** {} ***", announcement),
+                    Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                ),
+            );
+            return;
+        }
+        if let Some((level, log)) = line
+            .strip_prefix(LOG_PREFIX)
+            .and_then(|log| log.split_once(':'))
+        {
+            if let Some(level) = LogLevel::decode(level) {
+                let source = event_source(log).map(str::to_string);
+                self.log(source, Line::styled(log.to_string(), level_style(level)));
+                return;
+            }
+        }
+        let source = event_source(&line).map(str::to_string);
+        self.log(source, Line::raw(line));
+    }
+
+    /// Handles a key, returning the line to send to the broker if any
+    fn handle_key(&mut self, key: KeyEvent) -> Option<String> {
+        if let Some(prompt) = self.prompt.as_mut() {
+            match key.code {
+                KeyCode::Enter => {
+                    let prompt = self.prompt.take()?;
+                    if prompt.text.trim().is_empty() {
+                        return None;
+                    }
+                    return match prompt.kind {
+                        PromptKind::Match => Some(format!("MATCH:{}", prompt.text)),
+                        PromptKind::Announce => Some(format!("{}{}", ANNOUNCE_PREFIX, prompt.text)),
+                    };
+                }
+                KeyCode::Esc => self.prompt = None,
+                KeyCode::Backspace => {
+                    prompt.text.pop();
+                }
+                KeyCode::Char(c) => prompt.text.push(c),
+                _ => {}
+            }
+            return None;
+        }
+
+        let bots = self.board.as_ref().map(|b| b.bots.len()).unwrap_or(0);
+        match key.code {
+            KeyCode::Char(c) if c.is_ascii() && RefereeCommand::decode(c as u8).is_some() => {
+                Some(c.to_string())
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = self.selected.saturating_sub(1);
+                None
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(bots.saturating_sub(1));
+                None
+            }
+            KeyCode::Char(c @ ('+' | '=' | '-' | '1'..='9')) => {
+                let points = match c {
+                    '+' | '=' => 1,
+                    '-' => -1,
+                    digit => digit.to_digit(10)? as i64,
+                };
+                let bot = self.selected_bot()?;
+                Some(format!("SCORE:{}:{}", bot.name, points))
+            }
+            KeyCode::Char('n') => {
+                let text = self
+                    .current_match()
+                    .map(|m| {
+                        let limit = m
+                            .duration
+                            .map(|d| format!(":{}", d.as_secs()))
+                            .unwrap_or_default();
+                        format!("{}{}", m.bots.join(","), limit)
+                    })
+                    .unwrap_or_default();
+                self.prompt = Some(Prompt {
+                    kind: PromptKind::Match,
+                    text,
+                });
+                None
+            }
+            KeyCode::Char('a') => {
+                self.prompt = Some(Prompt {
+                    kind: PromptKind::Announce,
+                    text: String::new(),
+                });
+                None
+            }
+            KeyCode::Char('f') => {
+                self.filter = match self.filter {
+                    Some(_) => None,
+                    None => self.selected_bot().map(|bot| bot.name.clone()),
+                };
+                self.scroll = 0;
+                None
+            }
+            KeyCode::PageUp => {
+                self.scroll += 10;
+                None
+            }
+            KeyCode::PageDown => {
+                self.scroll = self.scroll.saturating_sub(10);
+                None
+            }
+            KeyCode::End => {
+                self.scroll = 0;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn bot_lines(&self) -> Vec<Line<'static>> {
+        let board = match &self.board {
+            Some(board) => board,
+            None => return vec![Line::raw("waiting for the broker")],
+        };
+        if board.bots.is_empty() {
+            return vec![Line::raw("no bots connected")];
+        }
+        let now = Local::now() + self.clock_offset;
+        board
+            .bots
+            .iter()
+            .enumerate()
+            .map(|(index, bot)| {
+                let state = match bot.disconnected_at {
+                    Some(disconnected_at) => format!(
+                        "disconnected {}",
+                        format_seconds((now - disconnected_at).num_seconds())
+                    ),
+                    None => bot.last_ack.clone().unwrap_or_else(|| "ready".to_string()),
+                };
+                let latency = bot
+                    .latency_ms
+                    .map(|l| format!("{:.1}ms", l))
+                    .unwrap_or_else(|| "-".to_string());
+                let team = bot
+                    .team
+                    .as_ref()
+                    .map(|t| format!(" ({})", t))
+                    .unwrap_or_default();
+                let mut style = Style::new();
+                if bot.disconnected_at.is_some() {
+                    style = style.fg(Color::DarkGray);
+                }
+                if self.current_match().map(|m| m.has_bot(&bot.name)) == Some(true) {
+                    style = style.add_modifier(Modifier::BOLD);
+                }
+                if index == self.selected {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                Line::styled(format!("{}{} {} {}", bot.name, team, state, latency), style)
+            })
+            .collect()
+    }
+
+    fn match_lines(&self) -> Vec<Line<'static>> {
+        let arena = match self.board.as_ref().and_then(|b| b.arena.as_ref()) {
+            Some(arena) => arena,
+            None => return vec![Line::raw("no arena, START and STOP go to all bots")],
+        };
+        let current = match &arena.current {
+            Some(current) => current,
+            None => return vec![Line::raw(format!("arena {}: no match yet", arena.name))],
+        };
+        let now = Local::now() + self.clock_offset;
+        let clock = match (current.started_at, current.ended_at, current.deadline()) {
+            (None, _, _) => Span::styled("WAITING", Style::new().fg(Color::Yellow)),
+            (Some(_), Some(_), _) => Span::styled(
+                format!(
+                    "ENDED after {}",
+                    format_seconds(current.elapsed(now).unwrap_or_default().as_secs() as i64)
+                ),
+                Style::new().fg(Color::Red),
+            ),
+            (Some(_), None, Some(deadline)) => Span::styled(
+                format!(
+                    "RUNNING {} left",
+                    format_seconds((deadline - now).num_seconds().max(0))
+                ),
+                Style::new().fg(Color::Green).add_modifier(Modifier::BOLD),
+            ),
+            (Some(_), None, None) => Span::styled(
+                format!(
+                    "RUNNING for {}",
+                    format_seconds(current.elapsed(now).unwrap_or_default().as_secs() as i64)
+                ),
+                Style::new().fg(Color::Green).add_modifier(Modifier::BOLD),
+            ),
+        };
+        let scores: Vec<String> = current
+            .bots
+            .iter()
+            .map(|bot| format!("{} {}", bot, current.scores.get(bot).unwrap_or(&0)))
+            .collect();
+        let standings: Vec<String> = arena
+            .standings()
+            .iter()
+            .map(|(bot, score)| format!("{} {}", bot, score))
+            .collect();
+        vec![
+            Line::raw(format!(
+                "arena {} match {}: {}",
+                arena.name,
+                current.number,
+                current.bots.join(" vs ")
+            )),
+            Line::from(clock),
+            Line::styled(
+                format!("score: {}", scores.join("  ")),
+                Style::new().add_modifier(Modifier::BOLD),
+            ),
+            Line::raw(format!(
+                "standings after {} matches: {}",
+                arena.played.len(),
+                standings.join(", ")
+            )),
+        ]
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [top, logs, bottom] = Layout::vertical([
+            Constraint::Length(8),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [bots, current] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(top);
+
+        let connection = if self.is_connected {
+            " bots "
+        } else {
+            " bots [disconnected] "
+        };
+        frame.render_widget(
+            Paragraph::new(self.bot_lines()).block(Block::bordered().title(connection)),
+            bots,
+        );
+        frame.render_widget(
+            Paragraph::new(self.match_lines()).block(Block::bordered().title(" match ")),
+            current,
+        );
+
+        let height = logs.height.saturating_sub(2) as usize;
+        let entries: Vec<&LogEntry> = self
+            .logs
+            .iter()
+            .filter(|entry| match &self.filter {
+                Some(filter) => entry.source.as_ref() == Some(filter),
+                None => true,
+            })
+            .collect();
+        let end = entries.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let lines: Vec<Line> = entries[start..end]
+            .iter()
+            .map(|entry| entry.line.clone())
+            .collect();
+        let title = match (&self.filter, self.scroll) {
+            (Some(filter), 0) => format!(" log of {} ", filter),
+            (None, 0) => " log ".to_string(),
+            (Some(filter), scroll) => format!(" log of {} ({} lines up) ", filter, scroll),
+            (None, scroll) => format!(" log ({} lines up) ", scroll),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            logs,
+        );
+
+        let bottom_line = match &self.prompt {
+            Some(prompt) => {
+                let label = match prompt.kind {
+                    PromptKind::Match => "next match <bot>,<bot>[:<seconds>]: ",
+                    PromptKind::Announce => "announce: ",
+                };
+                Line::from(vec![
+                    Span::styled(label, Style::new().add_modifier(Modifier::BOLD)),
+                    Span::raw(prompt.text.clone()),
+                    Span::styled("_", Style::new().add_modifier(Modifier::SLOW_BLINK)),
+                ])
+            }
+            None => Line::styled(HELP, Style::new().add_modifier(Modifier::DIM)),
+        };
+        frame.render_widget(Paragraph::new(bottom_line), bottom);
+    }
+}
+
+/// Reads keys on a thread of its own, since crossterm polling blocks
+fn spawn_key_reader() -> mpsc::UnboundedReceiver<KeyEvent> {
+    let (key_sender, key_receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !key_sender.is_closed() {
+            match event::poll(Duration::from_millis(100)) {
+                Ok(true) => {
+                    if let Ok(Event::Key(key)) = event::read() {
+                        if key.kind == KeyEventKind::Press && key_sender.send(key).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    key_receiver
+}
+
+async fn run_dashboard(
+    terminal: &mut DefaultTerminal,
+    addr: String,
+    mut claims: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    // Asking for the board right away also tells a reconnection has succeeded
+    claims.push("BOARD".to_string());
+    let (input_sender, input_receiver) = mpsc::channel(32);
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    spawn(client_connection(
+        addr,
+        claims,
+        input_receiver,
+        event_sender,
+    ));
+    let mut keys = spawn_key_reader();
+
+    let mut dashboard = Dashboard::new();
+    let mut redraw_interval = interval(REDRAW_INTERVAL);
+    let mut board_interval = interval(BOARD_POLL_INTERVAL);
+    loop {
+        terminal.draw(|frame| dashboard.draw(frame))?;
+        select! {
+            _ = redraw_interval.tick() => {}
+            _ = board_interval.tick() => {
+                if dashboard.is_connected {
+                    input_sender.try_send("BOARD".to_string()).ok();
+                }
+            }
+            event = event_receiver.recv() => match event {
+                Some(event) => dashboard.handle_event(event),
+                None => break,
+            },
+            key = keys.recv() => {
+                let key = match key {
+                    Some(key) => key,
+                    None => break,
+                };
+                let is_interrupt = key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL);
+                let is_quit = key.code == KeyCode::Char('q') && dashboard.prompt.is_none();
+                if is_interrupt || is_quit {
+                    break;
+                }
+                if let Some(line) = dashboard.handle_key(key) {
+                    input_sender.send(line).await?;
+                    input_sender.try_send("BOARD".to_string()).ok();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the referee dashboard until the referee quits
+pub async fn run(addr: String, claims: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut terminal = ratatui::init();
+    let result = run_dashboard(&mut terminal, addr, claims).await;
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(dashboard: &mut Dashboard, code: KeyCode) -> Option<String> {
+        dashboard.handle_key(KeyEvent::from(code))
+    }
+
+    fn board_bot(name: &str) -> BoardBot {
+        BoardBot {
+            name: name.to_string(),
+            team: None,
+            latency_ms: None,
+            last_ack: None,
+            disconnected_at: None,
+        }
+    }
+
+    fn dashboard_with_bots(names: &[&str]) -> Dashboard {
+        let mut dashboard = Dashboard::new();
+        dashboard.board = Some(Board {
+            time: Local::now(),
+            bots: names.iter().map(|name| board_bot(name)).collect(),
+            arena: None,
+        });
+        dashboard
+    }
+
+    #[test]
+    fn referee_hotkeys_ignore_non_ascii_keys() {
+        let mut dashboard = Dashboard::new();
+        assert_eq!(
+            press(&mut dashboard, KeyCode::Char('x')),
+            Some("x".to_string())
+        );
+        assert_eq!(
+            press(&mut dashboard, KeyCode::Char('Z')),
+            Some("Z".to_string())
+        );
+        // U+0178 and U+017A would truncate to 'x' and 'z'
+        assert_eq!(press(&mut dashboard, KeyCode::Char('\u{178}')), None);
+        assert_eq!(press(&mut dashboard, KeyCode::Char('\u{17a}')), None);
+    }
+
+    #[test]
+    fn score_keys_go_to_the_selected_bot() {
+        let mut dashboard = dashboard_with_bots(&["frog", "toad"]);
+        assert_eq!(
+            press(&mut dashboard, KeyCode::Char('+')),
+            Some("SCORE:frog:1".to_string())
+        );
+        press(&mut dashboard, KeyCode::Down);
+        press(&mut dashboard, KeyCode::Down);
+        assert_eq!(
+            press(&mut dashboard, KeyCode::Char('3')),
+            Some("SCORE:toad:3".to_string())
+        );
+        assert_eq!(
+            press(&mut dashboard, KeyCode::Char('-')),
+            Some("SCORE:toad:-1".to_string())
+        );
+        assert_eq!(press(&mut Dashboard::new(), KeyCode::Char('+')), None);
+    }
+
+    #[test]
+    fn announcement_prompt_sends_on_enter() {
+        let mut dashboard = Dashboard::new();
+        press(&mut dashboard, KeyCode::Char('a'));
+        for c in "next match!x".chars() {
+            assert_eq!(press(&mut dashboard, KeyCode::Char(c)), None);
+        }
+        press(&mut dashboard, KeyCode::Backspace);
+        assert_eq!(
+            press(&mut dashboard, KeyCode::Enter),
+            Some(format!("{}next match!", ANNOUNCE_PREFIX))
+        );
+        assert!(dashboard.prompt.is_none());
+    }
+
+    #[test]
+    fn event_lines_are_attributed_to_their_source() {
+        let line = "2024-05-01 14:00:00.123 +02:00:frog:connected";
+        assert_eq!(strip_event_time(line), Some("frog:connected"));
+        assert_eq!(event_source(line), Some("frog"));
+        assert_eq!(event_source("no time here"), None);
+        assert_eq!(format_seconds(75), "1:15");
+    }
+}
@@ -0,0 +1,375 @@
+//! Local actions triggered by broker events
+//!
+//! A hook runs a shell command, or writes to a named pipe, with the event as a line of
+//! JSON. Each hook has its own task that handles its events in order, and is cut short
+//! after a timeout, so a slow or stuck hook never holds up the broker loop.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command, spawn, sync::mpsc, time::timeout};
+
+use crate::arena::Match;
+
+/// Events waiting for a slow hook, beyond which new ones are dropped
+const HOOK_QUEUE_SIZE: usize = 64;
+
+/// The shell that runs command hooks, followed by the command
+#[cfg(unix)]
+const SHELL: [&str; 2] = ["sh", "-c"];
+#[cfg(not(unix))]
+const SHELL: [&str; 2] = ["cmd", "/C"];
+
+/// The broker events hooks can be attached to
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum HookEvent {
+    Start,
+    Stop,
+    TimeUp,
+    MatchNext,
+    MatchEnd,
+    Score,
+    EmergencyStop,
+    Announcement,
+    BotConnect,
+    BotDisconnect,
+}
+
+impl HookEvent {
+    pub const ALL: [HookEvent; 10] = [
+        HookEvent::Start,
+        HookEvent::Stop,
+        HookEvent::TimeUp,
+        HookEvent::MatchNext,
+        HookEvent::MatchEnd,
+        HookEvent::Score,
+        HookEvent::EmergencyStop,
+        HookEvent::Announcement,
+        HookEvent::BotConnect,
+        HookEvent::BotDisconnect,
+    ];
+
+    pub fn encode(&self) -> &'static str {
+        match self {
+            HookEvent::Start => "start",
+            HookEvent::Stop => "stop",
+            HookEvent::TimeUp => "time_up",
+            HookEvent::MatchNext => "match_next",
+            HookEvent::MatchEnd => "match_end",
+            HookEvent::Score => "score",
+            HookEvent::EmergencyStop => "emergency_stop",
+            HookEvent::Announcement => "announcement",
+            HookEvent::BotConnect => "bot_connect",
+            HookEvent::BotDisconnect => "bot_disconnect",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.encode() == text)
+    }
+}
+
+impl std::str::FromStr for HookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s).ok_or_else(|| format!("invalid hook event '{}'", s))
+    }
+}
+
+/// What the hook does with the event
+#[derive(Clone, Debug)]
+pub enum HookTarget {
+    Command(String),
+    Pipe(PathBuf),
+}
+
+/// A hook as given on the command line, like `time_up,match_end=./scoreboard.sh`
+#[derive(Clone, Debug)]
+pub struct Hook {
+    /// The events that trigger the hook, all of them when empty
+    pub events: Vec<HookEvent>,
+    pub target: HookTarget,
+}
+
+impl Hook {
+    fn parse(text: &str, target: impl FnOnce(&str) -> HookTarget) -> Result<Self, String> {
+        let (events, rest) = text
+            .split_once('=')
+            .ok_or_else(|| format!("hook '{}' is not <events>=<target>", text))?;
+        if rest.trim().is_empty() {
+            return Err(format!("hook '{}' has no target", text));
+        }
+        let events = match events.trim() {
+            "all" | "*" => Vec::new(),
+            events => events
+                .split(',')
+                .map(|event| event.trim().parse())
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self {
+            events,
+            target: target(rest),
+        })
+    }
+
+    /// Parses `<events>=<shell command>`
+    pub fn command(text: &str) -> Result<Self, String> {
+        Self::parse(text, |command| HookTarget::Command(command.to_string()))
+    }
+
+    /// Parses `<events>=<named pipe path>`
+    pub fn pipe(text: &str) -> Result<Self, String> {
+        Self::parse(text, |path| HookTarget::Pipe(PathBuf::from(path)))
+    }
+
+    fn describe(&self) -> String {
+        match &self.target {
+            HookTarget::Command(command) => format!("command '{}'", command),
+            HookTarget::Pipe(path) => format!("pipe {}", path.display()),
+        }
+    }
+}
+
+/// What hooks receive, as one line of JSON
+#[derive(Serialize, Clone, Debug)]
+pub struct HookPayload {
+    pub time: DateTime<Local>,
+    pub event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arena: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot: Option<String>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub current_match: Option<Match>,
+    /// Whether the bot was playing a running match, for disconnections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_match: Option<bool>,
+    pub message: String,
+}
+
+impl HookPayload {
+    pub fn new(event: HookEvent, message: &str) -> Self {
+        Self {
+            time: Local::now(),
+            event: event.encode(),
+            arena: None,
+            bot: None,
+            current_match: None,
+            in_match: None,
+            message: message.trim_end().to_string(),
+        }
+    }
+}
+
+struct HookWorker {
+    hook: Hook,
+    queue: mpsc::Sender<(HookEvent, Vec<u8>)>,
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    workers: Vec<HookWorker>,
+}
+
+impl Hooks {
+    /// Starts a task for each hook
+    pub fn new(hooks: Vec<Hook>, limit: Duration) -> Self {
+        let workers = hooks
+            .into_iter()
+            .map(|hook| {
+                let (queue, events) = mpsc::channel(HOOK_QUEUE_SIZE);
+                spawn(run(hook.clone(), events, limit));
+                HookWorker { hook, queue }
+            })
+            .collect();
+        Self { workers }
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        self.workers
+            .iter()
+            .map(|worker| {
+                let events = if worker.hook.events.is_empty() {
+                    "all events".to_string()
+                } else {
+                    worker
+                        .hook
+                        .events
+                        .iter()
+                        .map(HookEvent::encode)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                format!("{} on {}", worker.hook.describe(), events)
+            })
+            .collect()
+    }
+
+    /// Queues an event for its hooks without waiting for them
+    pub fn trigger(&self, event: HookEvent, payload: HookPayload) {
+        let mut workers = self
+            .workers
+            .iter()
+            .filter(|worker| worker.hook.events.is_empty() || worker.hook.events.contains(&event))
+            .peekable();
+        if workers.peek().is_none() {
+            return;
+        }
+        let mut json = match serde_json::to_vec(&payload) {
+            Ok(json) => json,
+            Err(err) => {
+                println!(
+                    "{}: cannot encode {} hook event: {}",
+                    Local::now(),
+                    event.encode(),
+                    err
+                );
+                return;
+            }
+        };
+        json.push(b'\n');
+        for worker in workers {
+            if worker.queue.try_send((event, json.clone())).is_err() {
+                println!(
+                    "{}: {} hook {} is falling behind, event dropped",
+                    Local::now(),
+                    event.encode(),
+                    worker.hook.describe()
+                );
+            }
+        }
+    }
+}
+
+/// Handles the events of a hook one at a time
+async fn run(hook: Hook, mut events: mpsc::Receiver<(HookEvent, Vec<u8>)>, limit: Duration) {
+    while let Some((event, json)) = events.recv().await {
+        let result = match &hook.target {
+            HookTarget::Command(command) => run_command(command, &json, limit).await,
+            HookTarget::Pipe(path) => write_pipe(path, &json, limit).await,
+        };
+        if let Err(err) = result {
+            println!(
+                "{}: {} hook {} failed: {}",
+                Local::now(),
+                event.encode(),
+                hook.describe(),
+                err
+            );
+        }
+    }
+}
+
+async fn run_command(command: &str, json: &[u8], limit: Duration) -> Result<(), String> {
+    let mut child = Command::new(SHELL[0])
+        .arg(SHELL[1])
+        .arg(command)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| err.to_string())?;
+    let stdin = child.stdin.take();
+    let completion = async {
+        // Hooks that do not read the event close stdin early, which is fine
+        if let Some(mut stdin) = stdin {
+            stdin.write_all(json).await.ok();
+        }
+        child.wait().await
+    };
+    match timeout(limit, completion).await {
+        Ok(Ok(status)) if status.success() => Ok(()),
+        Ok(Ok(status)) => Err(status.to_string()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(format!("killed after {}s", limit.as_secs_f32())),
+    }
+}
+
+#[cfg(unix)]
+async fn write_pipe(path: &Path, json: &[u8], limit: Duration) -> Result<(), String> {
+    // Opening fails at once when nothing reads the pipe, instead of blocking
+    let mut sender = tokio::net::unix::pipe::OpenOptions::new()
+        .open_sender(path)
+        .map_err(|err| match err.raw_os_error() {
+            Some(libc::ENXIO) => "nothing is reading the pipe".to_string(),
+            _ => err.to_string(),
+        })?;
+    match timeout(limit, sender.write_all(json)).await {
+        Ok(result) => result.map_err(|err| err.to_string()),
+        Err(_) => Err(format!("reader stalled for {}s", limit.as_secs_f32())),
+    }
+}
+
+#[cfg(not(unix))]
+async fn write_pipe(_path: &Path, _json: &[u8], _limit: Duration) -> Result<(), String> {
+    Err("named pipes are only supported on unix".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_events_round_trip() {
+        for event in HookEvent::ALL {
+            assert_eq!(event.encode().parse::<HookEvent>(), Ok(event));
+        }
+        assert!("reboot".parse::<HookEvent>().is_err());
+    }
+
+    #[test]
+    fn hooks_are_parsed() {
+        let hook = Hook::command("time_up, match_end=./scoreboard.sh --big").unwrap();
+        assert_eq!(hook.events, vec![HookEvent::TimeUp, HookEvent::MatchEnd]);
+        assert!(
+            matches!(&hook.target, HookTarget::Command(command) if command == "./scoreboard.sh --big")
+        );
+
+        let hook = Hook::pipe("all=/tmp/events").unwrap();
+        assert!(hook.events.is_empty());
+        assert!(matches!(&hook.target, HookTarget::Pipe(path) if path == Path::new("/tmp/events")));
+        assert!(Hook::pipe("*=/tmp/events").unwrap().events.is_empty());
+
+        assert!(Hook::command("./scoreboard.sh").is_err());
+        assert!(Hook::command("time_up= ").is_err());
+        assert!(Hook::command("time_up,reboot=./scoreboard.sh").is_err());
+    }
+
+    #[test]
+    fn payloads_leave_out_what_is_unknown() {
+        let mut payload = HookPayload::new(HookEvent::BotDisconnect, "frog left\n");
+        payload.bot = Some("frog".to_string());
+        let json: serde_json::Value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["event"], "bot_disconnect");
+        assert_eq!(json["bot"], "frog");
+        assert_eq!(json["message"], "frog left");
+        assert!(json.get("arena").is_none());
+        assert!(json.get("match").is_none());
+        assert!(json.get("in_match").is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn commands_get_the_event_and_are_cut_short() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("event.json");
+        let command = format!("cat > {}", path.display());
+        run_command(&command, b"{}\n", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}\n");
+
+        assert!(run_command("exit 3", b"", Duration::from_secs(5))
+            .await
+            .is_err());
+        let err = run_command("sleep 5", b"", Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(err.starts_with("killed after"));
+    }
+}
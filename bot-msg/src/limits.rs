@@ -0,0 +1,261 @@
+//! Flood protection for bot and client connections
+//!
+//! Every connection has a token bucket: each line spends a token, and tokens come back
+//! at the configured rate up to the burst size. Lines over the limit are handled by the
+//! overflow policy, and discarded lines are reported with a "N lines dropped" marker
+//! when the flood ends, or every `MARKER_INTERVAL` while it lasts.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use tokio::time::Instant;
+
+pub const MARKER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest dropped line quoted in summaries
+const MAX_QUOTED_LENGTH: usize = 80;
+
+/// What happens to the lines of a connection over its limit
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    /// Discard the lines, reporting how many
+    Drop,
+    /// Discard the lines, reporting how many of each kind and the last one
+    Summarise,
+    /// Close the connection
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    pub const ALL: [OverflowPolicy; 3] = [
+        OverflowPolicy::Drop,
+        OverflowPolicy::Summarise,
+        OverflowPolicy::Disconnect,
+    ];
+
+    pub fn encode(&self) -> &'static str {
+        match self {
+            OverflowPolicy::Drop => "drop",
+            OverflowPolicy::Summarise => "summarise",
+            OverflowPolicy::Disconnect => "disconnect",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|policy| policy.encode() == text)
+    }
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s).ok_or_else(|| format!("invalid overflow policy '{}'", s))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Lines per second, no limit when zero
+    pub rate: f64,
+    /// Lines accepted at once after a quiet period
+    pub burst: f64,
+    pub policy: OverflowPolicy,
+}
+
+/// What to do with a line
+pub enum Admission {
+    Accept,
+    Drop,
+    Disconnect,
+}
+
+/// The token bucket of a connection, with the lines it dropped since the last marker
+pub struct Limiter {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    dropped: u64,
+    dropped_since: Instant,
+    dropped_kinds: BTreeMap<&'static str, u64>,
+    last_dropped: Option<String>,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            tokens: limit.burst.max(1.0),
+            refilled_at: now,
+            dropped: 0,
+            dropped_since: now,
+            dropped_kinds: BTreeMap::new(),
+            last_dropped: None,
+        }
+    }
+
+    pub fn is_dropping(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Spends a token for a line, of some kind like a log level when it is worth summarising
+    pub fn admit(&mut self, kind: Option<&'static str>, line: Option<&str>) -> Admission {
+        if self.limit.rate <= 0.0 {
+            return Admission::Accept;
+        }
+        let now = Instant::now();
+        let refill = (now - self.refilled_at).as_secs_f64() * self.limit.rate;
+        self.tokens = (self.tokens + refill).min(self.limit.burst.max(1.0));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Admission::Accept;
+        }
+
+        if self.limit.policy == OverflowPolicy::Disconnect {
+            return Admission::Disconnect;
+        }
+        if self.dropped == 0 {
+            self.dropped_since = now;
+        }
+        self.dropped += 1;
+        if self.limit.policy == OverflowPolicy::Summarise {
+            if let Some(kind) = kind {
+                *self.dropped_kinds.entry(kind).or_default() += 1;
+            }
+            if let Some(line) = line {
+                self.last_dropped = Some(line.chars().take(MAX_QUOTED_LENGTH).collect());
+            }
+        }
+        Admission::Drop
+    }
+
+    /// Reports the lines dropped since the last marker, when the flood is over or
+    /// has been going on for a while
+    pub fn marker(&mut self, is_flood_over: bool) -> Option<String> {
+        if self.dropped == 0 || !(is_flood_over || self.dropped_since.elapsed() >= MARKER_INTERVAL)
+        {
+            return None;
+        }
+        let mut marker = format!(
+            "{} lines dropped over the limit of {} per second",
+            self.dropped, self.limit.rate
+        );
+        if !self.dropped_kinds.is_empty() {
+            let kinds = self
+                .dropped_kinds
+                .iter()
+                .map(|(kind, count)| format!("{} {}", count, kind))
+                .collect::<Vec<_>>()
+                .join(", ");
+            marker.push_str(&format!(" ({})", kinds));
+        }
+        if let Some(line) = self.last_dropped.take() {
+            marker.push_str(&format!(", last '{}'", line));
+        }
+        self.dropped = 0;
+        self.dropped_kinds.clear();
+        Some(marker)
+    }
+
+    pub fn disconnect_message(&self) -> String {
+        format!(
+            "over the limit of {} lines per second, disconnecting",
+            self.limit.rate
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: f64, policy: OverflowPolicy) -> Limiter {
+        Limiter::new(RateLimit {
+            rate,
+            burst,
+            policy,
+        })
+    }
+
+    #[test]
+    fn overflow_policies_round_trip() {
+        for policy in OverflowPolicy::ALL {
+            assert_eq!(policy.encode().parse::<OverflowPolicy>(), Ok(policy));
+        }
+        assert!("ignore".parse::<OverflowPolicy>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lines_within_the_burst_are_accepted() {
+        let mut limiter = limiter(10.0, 3.0, OverflowPolicy::Drop);
+        for _ in 0..3 {
+            assert!(matches!(limiter.admit(None, None), Admission::Accept));
+        }
+        assert!(matches!(limiter.admit(None, None), Admission::Drop));
+        assert!(limiter.is_dropping());
+
+        // One token comes back every 100ms
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(matches!(limiter.admit(None, None), Admission::Accept));
+        assert!(matches!(limiter.admit(None, None), Admission::Drop));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn markers_count_the_dropped_lines() {
+        let mut limiter = limiter(1.0, 1.0, OverflowPolicy::Drop);
+        limiter.admit(None, None);
+        limiter.admit(None, None);
+        limiter.admit(None, None);
+        assert_eq!(limiter.marker(false), None);
+        assert_eq!(
+            limiter.marker(true).as_deref(),
+            Some("2 lines dropped over the limit of 1 per second")
+        );
+        assert!(!limiter.is_dropping());
+        assert_eq!(limiter.marker(true), None);
+
+        // A long flood is reported while it lasts
+        limiter.admit(None, None);
+        tokio::time::advance(MARKER_INTERVAL / 2).await;
+        limiter.admit(None, None);
+        tokio::time::advance(MARKER_INTERVAL / 2).await;
+        assert!(limiter.marker(false).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summaries_tell_the_kinds_and_last_line() {
+        let mut limiter = limiter(1.0, 1.0, OverflowPolicy::Summarise);
+        limiter.admit(Some("info"), Some("first"));
+        limiter.admit(Some("info"), Some("hello"));
+        limiter.admit(Some("warn"), Some("low battery"));
+        limiter.admit(Some("info"), Some(&"x".repeat(200)));
+        assert_eq!(
+            limiter.marker(true).unwrap(),
+            format!(
+                "3 lines dropped over the limit of 1 per second (2 info, 1 warn), last '{}'",
+                "x".repeat(MAX_QUOTED_LENGTH)
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn floods_can_disconnect() {
+        let mut limiter = limiter(2.0, 1.0, OverflowPolicy::Disconnect);
+        assert!(matches!(limiter.admit(None, None), Admission::Accept));
+        assert!(matches!(limiter.admit(None, None), Admission::Disconnect));
+        assert!(!limiter.is_dropping());
+        assert_eq!(
+            limiter.disconnect_message(),
+            "over the limit of 2 lines per second, disconnecting"
+        );
+    }
+
+    #[test]
+    fn zero_rate_has_no_limit() {
+        let mut limiter = limiter(0.0, 0.0, OverflowPolicy::Disconnect);
+        for _ in 0..1000 {
+            assert!(matches!(limiter.admit(None, None), Admission::Accept));
+        }
+    }
+}
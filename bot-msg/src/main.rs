@@ -1,16 +1,46 @@
+mod arena;
+mod audit;
+mod bridge;
+mod clock;
+mod dashboard;
+mod hooks;
+mod limits;
+mod panic;
+mod recording;
+mod results;
+mod telemetry;
+
 use std::{
     collections::{BTreeMap, BTreeSet},
     error::Error,
     net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use arena::{Arena, Match, TournamentState};
+use audit::{AuditAction, AuditEntry, AuditLog};
+use clock::ClockSync;
+use dashboard::{Board, BoardBot, BOARD_PREFIX};
+use hooks::{Hook, HookEvent, HookPayload, Hooks};
+use limits::{Admission, Limiter, OverflowPolicy, RateLimit, MARKER_INTERVAL};
+use recording::{default_recording_path, read_recording, RecordedEvent, Recorder};
+use telemetry::{
+    ExportFormat, ExportOptions, TelemetryFrame, TelemetryLine, TelemetrySignal, TELEMETRY_MARKER,
 };
 
 use chrono::{DateTime, Local};
 use clap::{self, Parser};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
-    spawn,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    select, spawn,
     sync::{mpsc, oneshot},
+    time::{interval, timeout},
 };
 use ulid::Ulid;
 
@@ -33,9 +63,246 @@ pub struct Arguments {
 
 #[derive(Parser, Debug)]
 pub struct BrokerArguments {
-    /// Address
+    /// Address of both ports, unless listen addresses are given
     #[clap(short, long, default_value = "0.0.0.0")]
     pub address: String,
+    /// Accept bots on this IPv4 or IPv6 socket address, like 192.168.4.1:9001 or [::]:9001
+    #[clap(long)]
+    pub bot_listen: Vec<SocketAddr>,
+    /// Accept clients on this IPv4 or IPv6 socket address, like 10.0.0.5:9002 or [::1]:9002
+    #[clap(long)]
+    pub client_listen: Vec<SocketAddr>,
+    /// JSON file where arenas, matches and scores are kept across restarts
+    #[clap(short, long)]
+    pub state: Option<PathBuf>,
+    /// Record every event sent to clients to this file, as JSON lines
+    #[clap(short, long)]
+    pub record: Option<PathBuf>,
+    /// Append referee and operator actions to this audit log
+    #[clap(long)]
+    pub audit: Option<PathBuf>,
+    /// Run a shell command on events, as <events>=<command>, with the event as JSON on stdin
+    /// (events: start, stop, time_up, match_next, match_end, score, emergency_stop,
+    /// announcement, bot_connect, bot_disconnect, or all)
+    #[clap(long, value_parser = Hook::command)]
+    pub hook: Vec<Hook>,
+    /// Write events as JSON lines to a named pipe, as <events>=<path>
+    #[clap(long, value_parser = Hook::pipe)]
+    pub hook_pipe: Vec<Hook>,
+    /// Seconds after which a hook is killed, or its pipe write given up
+    #[clap(long, default_value = "5")]
+    pub hook_timeout: f64,
+    /// Lines or telemetry frames per second each bot may send, besides pongs, acks and
+    /// claims (0 for no limit)
+    #[clap(long, default_value = "100")]
+    pub bot_rate: f64,
+    /// Lines a bot may send at once after a quiet period
+    #[clap(long, default_value = "200")]
+    pub bot_burst: f64,
+    /// What to do with bot lines over the limit (drop, summarise, disconnect)
+    #[clap(long, default_value = "summarise")]
+    pub bot_overflow: OverflowPolicy,
+    /// Lines per second each client may send (0 for no limit)
+    #[clap(long, default_value = "20")]
+    pub client_rate: f64,
+    /// Lines a client may send at once after a quiet period
+    #[clap(long, default_value = "100")]
+    pub client_burst: f64,
+    /// What to do with client lines over the limit (drop, summarise, disconnect)
+    #[clap(long, default_value = "summarise")]
+    pub client_overflow: OverflowPolicy,
+}
+
+impl BrokerArguments {
+    fn bot_limit(&self) -> RateLimit {
+        RateLimit {
+            rate: self.bot_rate,
+            burst: self.bot_burst,
+            policy: self.bot_overflow,
+        }
+    }
+
+    fn client_limit(&self) -> RateLimit {
+        RateLimit {
+            rate: self.client_rate,
+            burst: self.client_burst,
+            policy: self.client_overflow,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct AuditArguments {
+    /// Audit log written by the broker
+    pub file: PathBuf,
+    /// Only show these actions (referee_claim, referee_command, arena_claim, match,
+    /// score, param_set, kick, rename, emergency_stop)
+    #[clap(short, long, value_delimiter = ',')]
+    pub action: Vec<AuditAction>,
+}
+
+#[derive(Parser, Debug)]
+pub struct SerialBridgeArguments {
+    /// Broker address
+    #[clap(short, long, default_value = "127.0.0.1")]
+    pub address: String,
+    /// Serial port of the bot, like /dev/ttyACM0
+    #[clap(short, long)]
+    pub port: String,
+    /// Baud rate, ignored by USB CDC-ACM ports
+    #[clap(long, default_value = "115200")]
+    pub baud_rate: u32,
+    /// Bot name
+    #[clap(short, long)]
+    pub name: String,
+    /// Team of the bot
+    #[clap(short, long)]
+    pub team: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct PanicDecodeArguments {
+    /// Dump saved by bot/get-panic.sh
+    pub file: PathBuf,
+    /// Firmware ELF, to find the functions at the panic location and addresses
+    #[clap(short, long)]
+    pub elf: Option<PathBuf>,
+    /// Bot that panicked, to attach the report to its log in a recording
+    #[clap(short, long)]
+    pub bot: Option<String>,
+    /// Recording where the report is appended as an error logged by the bot
+    #[clap(short, long, requires = "bot")]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(group(clap::ArgGroup::new("source").required(true).args(["state", "recording"])))]
+pub struct ResultsArguments {
+    /// Broker state file, with every match played
+    #[clap(short, long)]
+    pub state: Option<PathBuf>,
+    /// Recording written by the broker, when there is no state file
+    #[clap(short, long)]
+    pub recording: Option<PathBuf>,
+    /// Directory where results.html, bouts.csv and standings.csv are written
+    #[clap(short, long, default_value = ".")]
+    pub output: PathBuf,
+    /// Title of the results page
+    #[clap(short, long, default_value = "Tournament results")]
+    pub title: String,
+    /// Only publish these arenas, as name patterns with '*' and '?'
+    #[clap(short = 'A', long, value_delimiter = ',')]
+    pub arena: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct TelemetryArguments {
+    #[clap(subcommand)]
+    pub action: TelemetryAction,
+}
+
+#[derive(Parser, Debug)]
+pub enum TelemetryAction {
+    /// Write the telemetry of a recording to one file per bot, one column per signal
+    Export(TelemetryExportArguments),
+}
+
+#[derive(Parser, Debug)]
+pub struct TelemetryExportArguments {
+    /// Recording written by the broker
+    pub file: PathBuf,
+    /// Directory where the files are written
+    #[clap(short, long, default_value = ".")]
+    pub output: PathBuf,
+    /// File format (csv, or json with one array per column)
+    #[clap(short, long, default_value = "csv")]
+    pub format: ExportFormat,
+    /// Only export these bots, as name patterns with '*' and '?'
+    #[clap(short, long, value_delimiter = ',')]
+    pub bot: Vec<String>,
+    /// Broker state file, to find when matches were played
+    #[clap(short, long)]
+    pub state: Option<PathBuf>,
+    /// Only export a match, as <arena>[:<number>] (default: the latest match of the arena)
+    #[clap(short, long)]
+    pub r#match: Option<String>,
+    /// Seconds to keep before and after the match
+    #[clap(long, default_value = "0")]
+    pub margin: u64,
+    /// Start of the time window, as "2024-05-01 14:03:00" or RFC 3339
+    #[clap(long)]
+    pub from: Option<String>,
+    /// End of the time window, as "2024-05-01 14:05:00" or RFC 3339
+    #[clap(long)]
+    pub to: Option<String>,
+}
+
+impl TelemetryExportArguments {
+    /// What to export, with a time window from the match and the explicit bounds
+    pub fn export_options(&self) -> Result<ExportOptions, String> {
+        let (mut from, mut to) = (None, None);
+        if let Some(bout) = &self.r#match {
+            let path = self
+                .state
+                .as_ref()
+                .ok_or("finding a match needs the broker state file (--state)")?;
+            let state = TournamentState::load(path)
+                .map_err(|err| format!("cannot load {}: {}", path.display(), err))?;
+            let (arena, number) = match bout.split_once(':') {
+                Some((arena, number)) => (
+                    arena,
+                    Some(
+                        number
+                            .parse::<usize>()
+                            .map_err(|_| format!("invalid match number '{}'", number))?,
+                    ),
+                ),
+                None => (bout.as_str(), None),
+            };
+            let arena = state
+                .arenas
+                .get(arena)
+                .ok_or_else(|| format!("unknown arena '{}'", arena))?;
+            let mut matches = arena.played.iter().chain(arena.current.iter());
+            let played = match number {
+                Some(number) => matches.find(|m| m.number == number),
+                None => matches.rfind(|m| m.started_at.is_some()),
+            }
+            .ok_or_else(|| format!("no such match in arena {}", arena.name))?;
+            let margin = chrono::Duration::seconds(self.margin as i64);
+            from = Some(
+                played
+                    .started_at
+                    .ok_or_else(|| format!("match {} never started", played.number))?
+                    - margin,
+            );
+            to = played.ended_at.map(|ended_at| ended_at + margin);
+        }
+        if let Some(text) = &self.from {
+            from = Some(parse_time(text)?);
+        }
+        if let Some(text) = &self.to {
+            to = Some(parse_time(text)?);
+        }
+        Ok(ExportOptions {
+            bots: self.bot.clone(),
+            from,
+            to,
+            format: self.format,
+            output: self.output.clone(),
+        })
+    }
+}
+
+fn parse_time(text: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Local));
+    }
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(text, format).ok())
+        .and_then(|time| time.and_local_timezone(Local).single())
+        .ok_or_else(|| format!("invalid time '{}'", text))
 }
 
 #[derive(Parser, Debug)]
@@ -44,11 +311,64 @@ pub struct CmdArguments {
     #[clap(short, long, default_value = "127.0.0.1")]
     pub address: String,
     /// Bot name
+    #[clap(short, long, required_unless_present = "team")]
+    pub name: Option<String>,
+    /// Team name, to send commands to every bot of the team
     #[clap(short, long)]
-    pub name: String,
+    pub team: Option<String>,
     /// Also support referee commands
     #[clap(short, long)]
     pub referee: bool,
+    /// Follow the logs of these bots, as name patterns with '*' and '?' (default: this bot or team)
+    #[clap(short, long)]
+    pub follow: Vec<String>,
+    /// Minimum level of the bot logs to show
+    #[clap(short, long)]
+    pub level: Option<LogLevel>,
+    /// Kinds of events to show (logs, referee, commands, params, announcements, connections,
+    /// telemetry)
+    #[clap(short, long, value_delimiter = ',')]
+    pub events: Vec<String>,
+    /// Interaction mode (default: send bot commands)
+    #[clap(subcommand)]
+    pub mode: Option<CmdMode>,
+}
+
+#[derive(Parser, Debug, Clone, PartialEq)]
+pub enum CmdMode {
+    /// Browse and edit the parameters advertised by the bot
+    Params,
+    /// List the connected bots and clients, then exit
+    List,
+    /// Show the status of a bot and its clients (default: this bot), then exit
+    Status {
+        /// Bot name
+        name: Option<String>,
+    },
+    /// Show how the broker sees this client, then exit
+    Whoami,
+    /// Show the arenas with their matches and scores, then exit
+    Arenas,
+}
+
+impl CmdArguments {
+    pub fn subscriptions(&self) -> Vec<String> {
+        let mut subscriptions = Vec::new();
+        if !self.follow.is_empty() {
+            subscriptions.push(format!("bots={}", self.follow.join(",")));
+        } else if let Some(team) = &self.team {
+            subscriptions.push(format!("teams={}", team));
+        } else if let Some(name) = &self.name {
+            subscriptions.push(format!("bots={}", name));
+        }
+        if let Some(level) = self.level {
+            subscriptions.push(format!("level={}", level.encode()));
+        }
+        if !self.events.is_empty() {
+            subscriptions.push(format!("events={}", self.events.join(",")));
+        }
+        subscriptions
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -56,6 +376,22 @@ pub struct RefereeArguments {
     /// Address
     #[clap(short, long, default_value = "127.0.0.1")]
     pub address: String,
+    /// Arena to referee (default: all bots, without matches)
+    #[clap(short = 'A', long)]
+    pub arena: Option<String>,
+    /// Show a full screen dashboard with hotkeys instead of reading commands from stdin
+    #[clap(short, long)]
+    pub dashboard: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct SpectateArguments {
+    /// Address
+    #[clap(short, long, default_value = "127.0.0.1")]
+    pub address: String,
+    /// Follow only the events of these arenas, as name patterns with '*' and '?'
+    #[clap(short = 'A', long)]
+    pub arena: Vec<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -63,8 +399,20 @@ pub enum SubCommand {
     Broker(BrokerArguments),
     Cmd(CmdArguments),
     Referee(RefereeArguments),
+    Spectate(SpectateArguments),
+    /// Summarise an audit log written by the broker
+    Audit(AuditArguments),
+    /// Work with the telemetry in broker recordings
+    Telemetry(TelemetryArguments),
+    /// Connect a bot tethered over USB serial to the broker
+    SerialBridge(SerialBridgeArguments),
+    /// Decode the panic dump of a bot saved by bot/get-panic.sh
+    PanicDecode(PanicDecodeArguments),
+    /// Publish the results of a tournament as an HTML page and CSV files
+    Results(ResultsArguments),
 }
 
+#[derive(Clone, Copy)]
 pub enum RefereeCommand {
     Start,
     Stop,
@@ -73,34 +421,36 @@ pub enum RefereeCommand {
 impl RefereeCommand {
     pub fn encode(&self) -> u8 {
         match self {
-            RefereeCommand::Start => 'x' as u8,
-            RefereeCommand::Stop => 'z' as u8,
+            RefereeCommand::Start => b'x',
+            RefereeCommand::Stop => b'z',
         }
     }
 
     pub fn decode(byte: u8) -> Option<Self> {
-        if byte == 'x' as u8 || byte == 'X' as u8 {
+        if byte == b'x' || byte == b'X' {
             Some(Self::Start)
-        } else if byte == 'z' as u8 || byte == 'Z' as u8 {
+        } else if byte == b'z' || byte == b'Z' {
             Some(Self::Stop)
         } else {
             None
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RefereeCommand::Start => "START",
+            RefereeCommand::Stop => "STOP",
+        }
+    }
 }
 
 impl std::fmt::Display for RefereeCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!(
-            "referee command {}",
-            match self {
-                RefereeCommand::Start => "START",
-                RefereeCommand::Stop => "STOP",
-            }
-        ))
+        f.write_fmt(format_args!("referee command {}", self.name()))
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct PrivateCommand {
     byte: u8,
 }
@@ -111,11 +461,8 @@ impl PrivateCommand {
     }
 
     pub fn decode(byte: u8) -> Option<Self> {
-        if (byte >= 'a' as u8 && byte <= 'z' as u8)
-            || (byte >= 'A' as u8 && byte <= 'Z' as u8)
-            || (byte >= '0' as u8 && byte <= '9' as u8)
-        {
-            if let Some(_) = RefereeCommand::decode(byte) {
+        if byte.is_ascii_alphanumeric() {
+            if RefereeCommand::decode(byte).is_some() {
                 None
             } else {
                 Some(Self { byte })
@@ -152,6 +499,422 @@ impl BotCommand {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParamType {
+    Int,
+    Float,
+    Bool,
+}
+
+impl ParamType {
+    pub fn encode(&self) -> &'static str {
+        match self {
+            ParamType::Int => "int",
+            ParamType::Float => "float",
+            ParamType::Bool => "bool",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        match text {
+            "int" => Some(Self::Int),
+            "float" => Some(Self::Float),
+            "bool" => Some(Self::Bool),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum ParamValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ParamValue {
+    pub fn param_type(&self) -> ParamType {
+        match self {
+            ParamValue::Int(_) => ParamType::Int,
+            ParamValue::Float(_) => ParamType::Float,
+            ParamValue::Bool(_) => ParamType::Bool,
+        }
+    }
+
+    pub fn decode(param_type: ParamType, text: &str) -> Option<Self> {
+        match param_type {
+            ParamType::Int => text.parse().ok().map(Self::Int),
+            // NaN would pass any range check, as it compares false with everything
+            ParamType::Float => text
+                .parse()
+                .ok()
+                .filter(|value: &f64| value.is_finite())
+                .map(Self::Float),
+            ParamType::Bool => match text {
+                "1" | "true" | "on" => Some(Self::Bool(true)),
+                "0" | "false" | "off" => Some(Self::Bool(false)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamValue::Int(v) => v.fmt(f),
+            ParamValue::Float(v) => v.fmt(f),
+            ParamValue::Bool(v) => (if *v { "1" } else { "0" }).fmt(f),
+        }
+    }
+}
+
+/// A tunable value advertised by a bot
+///
+/// Bots advertise each parameter with a `PARAM:<name>:<type>:<value>[:<min>:<max>]`
+/// line, and advertise it again whenever its value changes.
+#[derive(Clone, Debug)]
+pub struct BotParam {
+    name: String,
+    value: ParamValue,
+    min: Option<ParamValue>,
+    max: Option<ParamValue>,
+}
+
+fn is_param_name_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+impl BotParam {
+    pub fn encode(&self) -> String {
+        let mut text = format!(
+            "{}:{}:{}",
+            self.name,
+            self.value.param_type().encode(),
+            self.value
+        );
+        if self.min.is_some() || self.max.is_some() {
+            text.push_str(&format!(
+                ":{}:{}",
+                self.min.map(|v| v.to_string()).unwrap_or_default(),
+                self.max.map(|v| v.to_string()).unwrap_or_default()
+            ));
+        }
+        text
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        let mut parts = text.split(':');
+        let name = parts.next()?;
+        if !is_param_name_valid(name) {
+            return None;
+        }
+        let param_type = ParamType::decode(parts.next()?)?;
+        let value = ParamValue::decode(param_type, parts.next()?)?;
+        let bound = |parts: &mut std::str::Split<char>| match parts.next() {
+            None | Some("") => Some(None),
+            Some(text) => ParamValue::decode(param_type, text).map(Some),
+        };
+        let min = bound(&mut parts)?;
+        let max = bound(&mut parts)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            value,
+            min,
+            max,
+        })
+    }
+
+    pub fn check(&self, text: &str) -> Result<ParamValue, String> {
+        let param_type = self.value.param_type();
+        let value = ParamValue::decode(param_type, text).ok_or_else(|| {
+            format!(
+                "invalid value '{}' for {} parameter {}",
+                text,
+                param_type.encode(),
+                self.name
+            )
+        })?;
+        if self.min.map(|min| value < min).unwrap_or(false)
+            || self.max.map(|max| value > max).unwrap_or(false)
+        {
+            return Err(format!(
+                "value {} out of range for parameter {} ({}..{})",
+                value,
+                self.name,
+                self.min.map(|v| v.to_string()).unwrap_or_default(),
+                self.max.map(|v| v.to_string()).unwrap_or_default()
+            ));
+        }
+        Ok(value)
+    }
+}
+
+impl std::fmt::Display for BotParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} = {} ({}",
+            self.name,
+            self.value,
+            self.value.param_type().encode()
+        ))?;
+        if self.min.is_some() || self.max.is_some() {
+            f.write_fmt(format_args!(
+                ", {}..{}",
+                self.min.map(|v| v.to_string()).unwrap_or_default(),
+                self.max.map(|v| v.to_string()).unwrap_or_default()
+            ))?;
+        }
+        f.write_str(")")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn encode(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl LogLevel {
+    /// Splits an optional `[level]` tag from the start of a bot log line
+    pub fn split_tag(line: &str) -> (Option<Self>, &str) {
+        line.strip_prefix('[')
+            .and_then(|rest| rest.split_once(']'))
+            .and_then(|(tag, rest)| Self::decode(tag.trim()).map(|level| (level, rest)))
+            .map(|(level, rest)| (Some(level), rest.strip_prefix(' ').unwrap_or(rest)))
+            .unwrap_or((None, line))
+    }
+
+    pub fn color(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "\x1b[31m",
+            LogLevel::Warn => "\x1b[33m",
+            LogLevel::Info => "",
+            LogLevel::Debug => "\x1b[36m",
+            LogLevel::Trace => "\x1b[2m",
+        }
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s).ok_or_else(|| format!("invalid log level '{}'", s))
+    }
+}
+
+/// The kinds of events the broker sends to clients
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum EventKind {
+    Logs,
+    Referee,
+    Commands,
+    Params,
+    Announcements,
+    Connections,
+    Telemetry,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 7] = [
+        EventKind::Logs,
+        EventKind::Referee,
+        EventKind::Commands,
+        EventKind::Params,
+        EventKind::Announcements,
+        EventKind::Connections,
+        EventKind::Telemetry,
+    ];
+
+    /// Telemetry is only sent to the clients that ask for it, to keep the logs readable
+    pub fn is_default(&self) -> bool {
+        *self != EventKind::Telemetry
+    }
+
+    pub fn encode(&self) -> &'static str {
+        match self {
+            EventKind::Logs => "logs",
+            EventKind::Referee => "referee",
+            EventKind::Commands => "commands",
+            EventKind::Params => "params",
+            EventKind::Announcements => "announcements",
+            EventKind::Connections => "connections",
+            EventKind::Telemetry => "telemetry",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.encode() == text)
+    }
+}
+
+/// Matches a name against a pattern where '*' matches any sequence and '?' any character
+fn name_matches(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|skip| matches(rest, &name[skip..])),
+            Some((p, rest)) => match name.split_first() {
+                Some((n, name_rest)) => (*p == '?' || p == n) && matches(rest, name_rest),
+                None => false,
+            },
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+/// What a client wants to receive
+///
+/// Clients change it with `SUBSCRIBE:<key>=<value>` lines, where the keys are
+/// `bots` (comma separated name patterns, `*` for all), `teams` (comma separated
+/// team patterns, `*` for all bots including those without a team), `arenas` (the
+/// same for arenas), `level` (minimum log level) and `events` (comma separated event
+/// kinds, `all` for every kind including telemetry).
+pub struct Subscription {
+    bots: Vec<String>,
+    teams: Vec<String>,
+    arenas: Vec<String>,
+    min_level: LogLevel,
+    events: BTreeSet<EventKind>,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            bots: vec!["*".to_string()],
+            teams: vec!["*".to_string()],
+            arenas: vec!["*".to_string()],
+            min_level: LogLevel::Trace,
+            events: EventKind::ALL
+                .into_iter()
+                .filter(EventKind::is_default)
+                .collect(),
+        }
+    }
+}
+
+impl Subscription {
+    pub fn update(&mut self, text: &str) -> Result<(), String> {
+        let (key, value) = text
+            .split_once('=')
+            .ok_or_else(|| format!("invalid subscription '{}'", text))?;
+        let patterns: Vec<String> = value
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+        match key {
+            "bots" => {
+                if patterns.is_empty() {
+                    return Err("no bots to subscribe to".to_string());
+                }
+                self.bots = patterns;
+            }
+            "teams" => {
+                if patterns.is_empty() {
+                    return Err("no teams to subscribe to".to_string());
+                }
+                self.teams = patterns;
+            }
+            "arenas" => {
+                if patterns.is_empty() {
+                    return Err("no arenas to subscribe to".to_string());
+                }
+                self.arenas = patterns;
+            }
+            "level" => {
+                self.min_level = value.parse()?;
+            }
+            "events" => {
+                self.events = if value == "all" {
+                    EventKind::ALL.into_iter().collect()
+                } else {
+                    value
+                        .split(',')
+                        .map(|e| {
+                            EventKind::decode(e.trim())
+                                .ok_or_else(|| format!("invalid event kind '{}'", e))
+                        })
+                        .collect::<Result<_, _>>()?
+                };
+            }
+            _ => return Err(format!("invalid subscription key '{}'", key)),
+        }
+        Ok(())
+    }
+
+    pub fn accepts(&self, kind: EventKind, source: &EventSource, level: Option<LogLevel>) -> bool {
+        let matches_any =
+            |patterns: &[String], name: &str| patterns.iter().any(|p| name_matches(p, name));
+        let is_scoped = source.bot.is_some() || source.arena.is_some();
+        self.events.contains(&kind)
+            && source
+                .bot
+                .map(|bot| matches_any(&self.bots, bot))
+                .unwrap_or(true)
+            && (source.bot.is_none() || matches_any(&self.teams, source.team.unwrap_or("")))
+            && (!is_scoped || matches_any(&self.arenas, source.arena.unwrap_or("")))
+            && level.map(|level| level <= self.min_level).unwrap_or(true)
+    }
+}
+
+impl std::fmt::Display for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let events: Vec<&str> = self.events.iter().map(EventKind::encode).collect();
+        f.write_fmt(format_args!(
+            "bots {} teams {} arenas {} level {} events {}",
+            self.bots.join(","),
+            self.teams.join(","),
+            self.arenas.join(","),
+            self.min_level.encode(),
+            events.join(",")
+        ))
+    }
+}
+
+/// Where an event comes from, to match it against client subscriptions
+#[derive(Default)]
+pub struct EventSource<'a> {
+    bot: Option<&'a str>,
+    team: Option<&'a str>,
+    arena: Option<&'a str>,
+}
+
 pub type BrokerResult = Result<(), String>;
 pub type BrokerResultSender = oneshot::Sender<BrokerResult>;
 pub type BrokerResultReceiver = oneshot::Receiver<BrokerResult>;
@@ -161,6 +924,7 @@ pub enum BrokerAction {
         id: Ulid,
         address: SocketAddr,
         writer: OwnedWriteHalf,
+        stop_reader: oneshot::Sender<()>,
         sender: BrokerResultSender,
     },
     BotNameClaim {
@@ -168,10 +932,21 @@ pub enum BrokerAction {
         name: String,
         sender: BrokerResultSender,
     },
+    BotResume {
+        id: Ulid,
+        token: String,
+        sender: BrokerResultSender,
+    },
+    BotTeamClaim {
+        id: Ulid,
+        team: String,
+        sender: BrokerResultSender,
+    },
     Join {
         id: Ulid,
         address: SocketAddr,
         writer: OwnedWriteHalf,
+        stop_reader: oneshot::Sender<()>,
         sender: BrokerResultSender,
     },
     NameClaim {
@@ -179,15 +954,40 @@ pub enum BrokerAction {
         name: String,
         sender: BrokerResultSender,
     },
+    TeamClaim {
+        id: Ulid,
+        team: String,
+        sender: BrokerResultSender,
+    },
     RefereeClaim {
         id: Ulid,
         sender: BrokerResultSender,
     },
+    ArenaClaim {
+        id: Ulid,
+        arena: String,
+        sender: BrokerResultSender,
+    },
+    SpectatorClaim {
+        id: Ulid,
+        sender: BrokerResultSender,
+    },
+    Subscribe {
+        id: Ulid,
+        subscription: String,
+    },
     Log {
         id: Ulid,
         time: DateTime<Local>,
+        bot_ms: Option<u64>,
+        level: LogLevel,
         message: String,
     },
+    BotSync {
+        id: Ulid,
+        received: Instant,
+        bot_ms: u64,
+    },
     RefereeCommand {
         id: Ulid,
         time: DateTime<Local>,
@@ -197,61 +997,309 @@ pub enum BrokerAction {
         id: Ulid,
         time: DateTime<Local>,
         command: PrivateCommand,
+        queue_for: Option<Duration>,
     },
-    BotLeave {
+    TeamCommand {
         id: Ulid,
+        time: DateTime<Local>,
+        bot: Option<String>,
+        command: PrivateCommand,
     },
-    Leave {
+    NewMatch {
         id: Ulid,
+        time: DateTime<Local>,
+        bots: Vec<String>,
+        duration: Option<Duration>,
     },
-}
-
-pub type BrokerActionSender = mpsc::Sender<BrokerAction>;
-pub type BrokerActionReceiver = mpsc::Receiver<BrokerAction>;
-
-pub struct BrokerBot {
-    id: Ulid,
-    name: Option<String>,
-    address: SocketAddr,
-    writer: OwnedWriteHalf,
-}
-
-impl BrokerBot {
-    pub fn has_name(&self, name: &str) -> bool {
-        self.name.as_ref().map(|n| n == name).unwrap_or(false)
-    }
-}
-
-pub struct BrokerClient {
-    id: Ulid,
-    name: Option<String>,
+    Score {
+        id: Ulid,
+        time: DateTime<Local>,
+        bot: String,
+        points: i64,
+    },
+    Announce {
+        id: Ulid,
+        time: DateTime<Local>,
+        text: String,
+        to_bots: bool,
+    },
+    ClientError {
+        id: Ulid,
+        message: String,
+    },
+    BotPong {
+        id: Ulid,
+        time: Instant,
+    },
+    BotLines {
+        id: Ulid,
+    },
+    BotAck {
+        id: Ulid,
+        time: DateTime<Local>,
+        command: RefereeCommand,
+    },
+    List {
+        id: Ulid,
+    },
+    Status {
+        id: Ulid,
+        name: String,
+    },
+    Whoami {
+        id: Ulid,
+    },
+    Arenas {
+        id: Ulid,
+    },
+    Board {
+        id: Ulid,
+    },
+    BotParam {
+        id: Ulid,
+        time: DateTime<Local>,
+        param: BotParam,
+    },
+    BotSignal {
+        id: Ulid,
+        time: DateTime<Local>,
+        signal: TelemetrySignal,
+    },
+    BotTelemetry {
+        id: Ulid,
+        time: DateTime<Local>,
+        frame: TelemetryFrame,
+    },
+    ParamList {
+        id: Ulid,
+    },
+    ParamGet {
+        id: Ulid,
+        name: String,
+    },
+    ParamSet {
+        id: Ulid,
+        time: DateTime<Local>,
+        name: String,
+        value: String,
+    },
+    /// Lines of a bot or client discarded over its rate limit
+    Dropped {
+        id: Ulid,
+        time: DateTime<Local>,
+        message: String,
+    },
+    BotLeave {
+        id: Ulid,
+    },
+    Leave {
+        id: Ulid,
+    },
+    Console {
+        line: String,
+    },
+}
+
+pub type BrokerActionSender = mpsc::Sender<BrokerAction>;
+pub type BrokerActionReceiver = mpsc::Receiver<BrokerAction>;
+
+/// A bot connected to the bot port
+///
+/// Bots are pinged with a zero byte every `PING_INTERVAL`; those that answer with a
+/// `PONG` line get their round trip latency measured.
+pub struct BrokerBot {
+    id: Ulid,
+    name: Option<String>,
+    team: Option<String>,
+    session: Option<String>,
+    address: SocketAddr,
+    writer: OwnedWriteHalf,
+    /// Stops the task reading from the bot when sent or dropped, closing the connection
+    stop_reader: oneshot::Sender<()>,
+    connected_at: DateTime<Local>,
+    last_seen: DateTime<Local>,
+    last_log: Option<DateTime<Local>>,
+    ping_sent: Option<Instant>,
+    latency: Option<Duration>,
+    clock: ClockSync,
+    last_ack: Option<RefereeCommand>,
+    /// Whether the bot said it reads `#` lines, with a `LINES` line
+    is_line_framed: bool,
+    params: BTreeMap<String, BotParam>,
+    param_setters: BTreeMap<String, String>,
+    signals: BTreeMap<u8, TelemetrySignal>,
+}
+
+impl BrokerBot {
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name.as_ref().map(|n| n == name).unwrap_or(false)
+    }
+
+    pub fn in_team(&self, team: &str) -> bool {
+        self.team.as_ref().map(|t| t == team).unwrap_or(false)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "bot {}{} at {} connected {} last log {} latency {} {}",
+            self.name.as_deref().unwrap_or("(unnamed)"),
+            self.team
+                .as_ref()
+                .map(|t| format!(" (team {})", t))
+                .unwrap_or_default(),
+            self.address,
+            self.connected_at.format(TIME_FORMAT),
+            self.last_log
+                .map(|t| t.format(TIME_FORMAT).to_string())
+                .unwrap_or_else(|| "never".to_string()),
+            self.latency
+                .map(|l| format!("{:.1}ms", l.as_secs_f64() * 1000.0))
+                .unwrap_or_else(|| "unknown".to_string()),
+            self.clock.describe(),
+        )
+    }
+}
+
+pub struct BrokerClient {
+    id: Ulid,
+    name: Option<String>,
+    team: Option<String>,
     is_referee: bool,
+    is_spectator: bool,
+    arena: Option<String>,
     address: SocketAddr,
     writer: OwnedWriteHalf,
+    /// Stops the task reading from the client when sent or dropped, closing the connection
+    stop_reader: oneshot::Sender<()>,
+    connected_at: DateTime<Local>,
+    subscription: Subscription,
 }
 
 impl BrokerClient {
     pub fn has_name(&self, name: &str) -> bool {
         self.name.as_ref().map(|n| n == name).unwrap_or(false)
     }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "client {}{}{}{} at {} connected {}",
+            self.name.as_deref().unwrap_or("(unnamed)"),
+            self.team
+                .as_ref()
+                .map(|t| format!(" (team {})", t))
+                .unwrap_or_default(),
+            if self.is_referee {
+                " [REFEREE]"
+            } else if self.is_spectator {
+                " [SPECTATOR]"
+            } else {
+                ""
+            },
+            self.arena
+                .as_ref()
+                .map(|a| format!(" in arena {}", a))
+                .unwrap_or_default(),
+            self.address,
+            self.connected_at.format(TIME_FORMAT),
+        )
+    }
+}
+
+/// The identity of a named bot, kept across reconnections
+///
+/// A bot that sent `LINES` gets a `#SESSION:<token>` line when it claims a name, and
+/// after losing its connection it can send `RESUME:<token>` instead of claiming its
+/// name again, and it gets back its team too. Bots that did not send `LINES` get no
+/// session, as they would read the token as commands.
+pub struct BotSession {
+    name: String,
+    team: Option<String>,
+    bot_id: Ulid,
+    disconnected_at: Option<DateTime<Local>>,
+}
+
+/// A private command waiting for its bot to connect
+pub struct QueuedCommand {
+    command: PrivateCommand,
+    client: String,
+    queued_at: DateTime<Local>,
+    expires_at: DateTime<Local>,
 }
 
 pub struct Broker {
     bots: BTreeMap<Ulid, BrokerBot>,
     clients: BTreeMap<Ulid, BrokerClient>,
+    queued_commands: BTreeMap<String, Vec<QueuedCommand>>,
+    sessions: BTreeMap<String, BotSession>,
+    tournament: TournamentState,
+    state_file: Option<PathBuf>,
+    recorder: Option<Recorder>,
+    recording_path: Option<PathBuf>,
+    audit_log: Option<AuditLog>,
+    hooks: Hooks,
+    stop_acks_pending: BTreeSet<Ulid>,
+    is_shutting_down: bool,
+    /// When the broker started, the zero of the broker time sent to bots
+    epoch: Instant,
+    epoch_time: DateTime<Local>,
+}
+
+/// A connection picked by the operator on the broker console
+enum ConsoleTarget {
+    Bot(Ulid),
+    Client(Ulid),
 }
 
 const PING_LINE: &str = "\n";
 
+/// Marks broker to bot messages that are whole lines instead of single command bytes
+///
+/// Bots read every other byte as a command, so lines they did not ask for only go to
+/// bots that sent a `LINES` line first: a bot that does not know about `#` lines would
+/// run the letters of the text as commands. Replies to lines sent by the bot itself,
+/// like `#SET:` for its parameters or `#SYNC:`, need no such opt in.
+const BOT_LINE_PREFIX: &str = "#";
+
+/// Marks referee announcements sent to clients and bots
+const ANNOUNCE_PREFIX: &str = "ANNOUNCE:";
+
+/// Marks bot log lines sent to clients, followed by the log level
+const LOG_PREFIX: &str = "LOG:";
+
+/// Marks errors sent back to the client that caused them
+const ERROR_PREFIX: &str = "ERROR:";
+
+/// Marks query replies sent back to the client that asked
+const INFO_PREFIX: &str = "INFO:";
+/// Terminates the replies to a single query
+const INFO_END: &str = "INFO_END";
+
+const CONSOLE_HELP: &str = "\
+console commands:
+  list                                      list the connected bots and clients
+  kick <bot|client> <name or address>       disconnect a bot or client
+  stop                                      send STOP to every bot and end the running matches
+  rename <bot|client> <name or address>=<new name>
+                                            change the name of a bot or client
+  unname <bot|client> <name or address>     remove the name of a bot or client
+  record [<file>]                           toggle recording, or record to another file
+  dump                                      print the internal broker state
+  quit                                      stop every bot and shut the broker down";
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const MATCH_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_QUEUE_TIME: Duration = Duration::from_secs(3600);
+const SESSION_TIMEOUT: Duration = Duration::from_secs(600);
+const SHUTDOWN_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
 fn is_name_valid(name: &str) -> bool {
     for c in name.chars() {
-        let valid_char = (c >= 'a' && c <= 'z')
-            || (c >= 'A' && c <= 'Z')
-            || (c >= '0' && c <= '9')
-            || (c == ' ')
-            || (c == '-')
-            || (c == '_')
-            || (c == '.');
+        let valid_char =
+            c.is_ascii_alphanumeric() || (c == ' ') || (c == '-') || (c == '_') || (c == '.');
         if !valid_char {
             return false;
         }
@@ -265,11 +1313,205 @@ fn is_name_valid(name: &str) -> bool {
     true
 }
 
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Broker {
     pub fn new() -> Self {
         Self {
             bots: BTreeMap::new(),
             clients: BTreeMap::new(),
+            queued_commands: BTreeMap::new(),
+            sessions: BTreeMap::new(),
+            tournament: TournamentState::default(),
+            state_file: None,
+            recorder: None,
+            recording_path: None,
+            audit_log: None,
+            hooks: Hooks::default(),
+            stop_acks_pending: BTreeSet::new(),
+            is_shutting_down: false,
+            epoch: Instant::now(),
+            epoch_time: Local::now(),
+        }
+    }
+
+    pub fn start_recording(&mut self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        self.stop_recording();
+        self.recorder = Some(
+            Recorder::start(path.clone())
+                .map_err(|err| format!("cannot record to {}: {}", path.display(), err))?,
+        );
+        println!("{}: recording to {}", Local::now(), path.display());
+        self.recording_path = Some(path);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.flush_recording();
+        if let Some(recorder) = self.recorder.take() {
+            println!(
+                "{}: stopped recording to {}",
+                Local::now(),
+                recorder.path().display()
+            );
+        }
+    }
+
+    pub fn flush_recording(&mut self) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(err) = recorder.flush() {
+                println!(
+                    "{}: error recording to {}: {}",
+                    Local::now(),
+                    recorder.path().display(),
+                    err
+                );
+            }
+        }
+    }
+
+    pub fn open_audit_log(&mut self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        self.audit_log = Some(
+            AuditLog::open(path.clone())
+                .map_err(|err| format!("cannot open audit log {}: {}", path.display(), err))?,
+        );
+        println!("{}: auditing to {}", Local::now(), path.display());
+        Ok(())
+    }
+
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        for hook in hooks.describe() {
+            println!("{}: hook {}", Local::now(), hook);
+        }
+        self.hooks = hooks;
+    }
+
+    /// Runs the hooks of an event in an arena, passing them its current match
+    fn arena_hook(&self, event: HookEvent, arena: &str, bot: Option<&str>, message: &str) {
+        let mut payload = HookPayload::new(event, message);
+        payload.arena = Some(arena.to_string());
+        payload.bot = bot.map(str::to_string);
+        payload.current_match = self
+            .tournament
+            .arenas
+            .get(arena)
+            .and_then(|a| a.current.clone());
+        self.hooks.trigger(event, payload);
+    }
+
+    /// Runs the hooks of an event about a bot, telling whether it is in a running match
+    fn bot_hook(&self, event: HookEvent, name: &str, message: &str) {
+        let mut payload = HookPayload::new(event, message);
+        payload.bot = Some(name.to_string());
+        if let Some(arena) = self.tournament.bot_arena(name) {
+            payload.arena = Some(arena.name.clone());
+            payload.current_match = arena.current.clone();
+        }
+        payload.in_match = Some(
+            payload
+                .current_match
+                .as_ref()
+                .is_some_and(Match::is_running),
+        );
+        self.hooks.trigger(event, payload);
+    }
+
+    /// Appends an action of a client, or of the operator if there is no client, to the audit log
+    fn audit(&mut self, id: Option<Ulid>, action: AuditAction, detail: String) {
+        if self.audit_log.is_none() {
+            return;
+        }
+        let entry = match id {
+            Some(id) => AuditEntry {
+                time: Local::now(),
+                action: action.encode().to_string(),
+                actor: self.client_info(id),
+                address: self.clients.get(&id).map(|c| c.address.to_string()),
+                client: Some(id.to_string()),
+                detail,
+            },
+            None => AuditEntry {
+                time: Local::now(),
+                action: action.encode().to_string(),
+                actor: "operator".to_string(),
+                address: None,
+                client: None,
+                detail,
+            },
+        };
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            if let Err(err) = audit_log.append(&entry) {
+                println!(
+                    "{}: error writing audit log {}: {}",
+                    Local::now(),
+                    audit_log.path().display(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn record_event(
+        &mut self,
+        kind: EventKind,
+        source: &EventSource,
+        level: Option<LogLevel>,
+        message: &str,
+    ) {
+        let recorder = match self.recorder.as_mut() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let event = RecordedEvent {
+            time: Local::now(),
+            kind: kind.encode().to_string(),
+            bot: source.bot.map(str::to_string),
+            team: source.team.map(str::to_string),
+            arena: source.arena.map(str::to_string),
+            level: level.map(|level| level.encode().to_string()),
+            message: message.trim_end().to_string(),
+        };
+        if let Err(err) = recorder.record(&event) {
+            println!(
+                "{}: error recording to {}: {}, recording stopped",
+                Local::now(),
+                recorder.path().display(),
+                err
+            );
+            self.recorder = None;
+        }
+    }
+
+    /// Keeps the tournament state in this file, starting from its content if it exists
+    pub fn load_state(&mut self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        if path.exists() {
+            self.tournament = TournamentState::load(&path)
+                .map_err(|err| format!("cannot load state from {}: {}", path.display(), err))?;
+            println!(
+                "{}: loaded {} arenas from {}",
+                Local::now(),
+                self.tournament.arenas.len(),
+                path.display()
+            );
+        }
+        self.state_file = Some(path);
+        Ok(())
+    }
+
+    fn save_state(&self) {
+        if let Some(path) = &self.state_file {
+            if let Err(err) = self.tournament.save(path) {
+                println!(
+                    "{}: error saving state to {}: {}",
+                    Local::now(),
+                    path.display(),
+                    err
+                );
+            }
         }
     }
 
@@ -295,6 +1537,8 @@ impl Broker {
                     } else {
                         "REFEREE".to_string()
                     }
+                } else if c.is_spectator {
+                    format!("{}[SPECTATOR]", c.address)
                 } else {
                     c.name
                         .as_ref()
@@ -305,10 +1549,32 @@ impl Broker {
             .unwrap_or_else(|| format!("unknown client {}", id))
     }
 
+    fn is_spectator(&self, id: Ulid) -> bool {
+        self.clients
+            .get(&id)
+            .map(|c| c.is_spectator)
+            .unwrap_or(false)
+    }
+
+    /// The team of the bot with this name, also while it is away within its session
+    fn bot_team(&self, name: &str) -> Option<String> {
+        self.bots
+            .values()
+            .find(|b| b.has_name(name))
+            .map(|b| b.team.clone())
+            .or_else(|| {
+                self.sessions
+                    .values()
+                    .find(|s| s.name == name)
+                    .map(|s| s.team.clone())
+            })
+            .flatten()
+    }
+
     fn send_bot_result(&mut self, id: Ulid, sender: BrokerResultSender, result: BrokerResult) {
         if sender.send(result).is_err() {
             println!("{}: removing bot {}", Local::now(), self.bot_info(id));
-            self.bots.remove(&id);
+            self.remove_bot(id, "disconnected");
         }
     }
 
@@ -319,12 +1585,40 @@ impl Broker {
         }
     }
 
+    /// Removes a bot for some reason, letting its hooks and its session know
+    fn remove_bot(&mut self, id: Ulid, reason: &str) -> Option<BrokerBot> {
+        let bot_info = self.bot_info(id);
+        let name = self.bots.get(&id)?.name.clone();
+        self.stop_acks_pending.remove(&id);
+        if let Some(name) = name {
+            let message = format!("{}:{}:{}", Local::now(), &bot_info, reason);
+            self.bot_hook(HookEvent::BotDisconnect, &name, &message);
+        }
+        let bot = self.bots.remove(&id)?;
+        if let Some(session) = bot
+            .session
+            .as_ref()
+            .and_then(|token| self.sessions.get_mut(token))
+        {
+            if session.bot_id == id {
+                session.disconnected_at = Some(Local::now());
+            }
+        }
+        Some(bot)
+    }
+
     fn remove_dead_bot(&mut self, id: Ulid) {
+        if !self.bots.contains_key(&id) {
+            return;
+        }
         println!("{}: disconected bot {}", Local::now(), self.bot_info(id));
-        self.clients.remove(&id);
+        self.remove_bot(id, "disconnected");
     }
 
     fn remove_dead_client(&mut self, id: Ulid) {
+        if !self.clients.contains_key(&id) {
+            return;
+        }
         println!(
             "{}: disconected client {}",
             Local::now(),
@@ -333,6 +1627,169 @@ impl Broker {
         self.clients.remove(&id);
     }
 
+    pub async fn measure_latency(&mut self) {
+        let message = [0u8];
+        let mut dead_bot_ids = Vec::new();
+        for bot in self.bots.values_mut() {
+            if bot.writer.write_all(&message).await.is_err() {
+                dead_bot_ids.push(bot.id);
+            } else {
+                // Measuring from an older ping, whose PONG was lost, would inflate the latency
+                bot.ping_sent = Some(Instant::now());
+            }
+        }
+        for id in dead_bot_ids {
+            self.remove_dead_bot(id);
+        }
+    }
+
+    pub async fn bot_ack(&mut self, id: Ulid, time: DateTime<Local>, command: RefereeCommand) {
+        match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.last_seen = time;
+                bot.last_ack = Some(command);
+            }
+            None => return,
+        }
+        if let RefereeCommand::Stop = command {
+            self.stop_acks_pending.remove(&id);
+        }
+        let bot_info = self.bot_info(id);
+        let message = format!("{}:{}:acknowledges {}\n", time, &bot_info, command);
+        print!("{}", &message);
+        self.write_event(EventKind::Referee, Some(&bot_info), None, &message)
+            .await;
+    }
+
+    /// Answers a sync request with the broker time, and samples the bot clock offset
+    pub async fn bot_sync(&mut self, id: Ulid, received: Instant, bot_ms: u64) {
+        let broker_ms = received.duration_since(self.epoch).as_secs_f64() * 1000.0;
+        let bot = match self.bots.get_mut(&id) {
+            Some(bot) => bot,
+            None => return,
+        };
+        bot.last_seen = Local::now();
+        let latency_ms = bot.latency.map(|l| l.as_secs_f64() * 1000.0);
+        bot.clock
+            .add(broker_ms, bot_ms as f64, latency_ms.unwrap_or_default());
+        let message = format!("{}SYNC:{}:{}\n", BOT_LINE_PREFIX, bot_ms, broker_ms as u64);
+        if bot.writer.write_all(message.as_bytes()).await.is_err() {
+            self.remove_dead_bot(id);
+        }
+    }
+
+    pub async fn bot_pong(&mut self, id: Ulid, time: Instant) {
+        if let Some(bot) = self.bots.get_mut(&id) {
+            bot.last_seen = Local::now();
+            if let Some(ping_sent) = bot.ping_sent.take() {
+                bot.latency = Some(time.duration_since(ping_sent));
+            }
+        }
+    }
+
+    /// Notes that a bot reads `#` lines, starting its session if it has a name already
+    pub async fn bot_lines(&mut self, id: Ulid) {
+        let name = match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.is_line_framed = true;
+                match (&bot.name, &bot.session) {
+                    (Some(name), None) => name.clone(),
+                    _ => return,
+                }
+            }
+            None => return,
+        };
+        self.start_session(id, &name).await;
+    }
+
+    async fn write_info(&mut self, id: Ulid, lines: Vec<String>) {
+        let mut message = String::new();
+        for line in lines {
+            message.push_str(&format!("{}{}\n", INFO_PREFIX, line));
+        }
+        message.push_str(&format!("{}\n", INFO_END));
+        self.write_to_client(id, &message).await;
+    }
+
+    fn referee_info(&self) -> String {
+        let referees = self.clients.values().filter(|c| c.is_referee).count();
+        match referees {
+            0 => "no referee connected".to_string(),
+            1 => "referee connected".to_string(),
+            n => format!("{} referees connected", n),
+        }
+    }
+
+    pub async fn list(&mut self, id: Ulid) {
+        let mut lines: Vec<String> = self.bots.values().map(BrokerBot::describe).collect();
+        lines.extend(self.clients.values().map(BrokerClient::describe));
+        lines.push(self.referee_info());
+        self.write_info(id, lines).await;
+    }
+
+    pub async fn status(&mut self, id: Ulid, name: String) {
+        let mut lines: Vec<String> = self
+            .bots
+            .values()
+            .filter(|b| b.has_name(&name))
+            .map(BrokerBot::describe)
+            .collect();
+        if lines.is_empty() {
+            lines.push(format!("bot {} not connected", &name));
+        }
+        if let Some(queued_commands) = self.queued_commands.get(&name) {
+            lines.extend(queued_commands.iter().map(|queued| {
+                format!(
+                    "queued {} from {} until {}",
+                    queued.command,
+                    queued.client,
+                    queued.expires_at.format(TIME_FORMAT)
+                )
+            }));
+        }
+        lines.extend(
+            self.clients
+                .values()
+                .filter(|c| c.has_name(&name))
+                .map(BrokerClient::describe),
+        );
+        lines.push(self.referee_info());
+        self.write_info(id, lines).await;
+    }
+
+    pub async fn whoami(&mut self, id: Ulid) {
+        let (mut lines, name, team) = match self.clients.get(&id) {
+            Some(client) => (
+                vec![client.describe()],
+                client.name.clone(),
+                client.team.clone(),
+            ),
+            None => return,
+        };
+        let is_own_bot = |b: &BrokerBot| {
+            name.as_ref().map(|name| b.has_name(name)).unwrap_or(false)
+                || team.as_ref().map(|team| b.in_team(team)).unwrap_or(false)
+        };
+        let bots: Vec<String> = self
+            .bots
+            .values()
+            .filter(|b| is_own_bot(b))
+            .map(BrokerBot::describe)
+            .collect();
+        if let Some(name) = &name {
+            if !self.bots.values().any(|b| b.has_name(name)) {
+                lines.push(format!("bot {} not connected", name));
+            }
+        }
+        if let Some(team) = &team {
+            if !self.bots.values().any(|b| b.in_team(team)) {
+                lines.push(format!("no bots of team {} connected", team));
+            }
+        }
+        lines.extend(bots);
+        self.write_info(id, lines).await;
+    }
+
     async fn ping_bots(&mut self) {
         let message = [0u8];
         let mut dead_bot_ids = Vec::new();
@@ -407,6 +1864,7 @@ impl Broker {
         id: Ulid,
         address: SocketAddr,
         writer: OwnedWriteHalf,
+        stop_reader: oneshot::Sender<()>,
         sender: BrokerResultSender,
     ) {
         self.bots.insert(
@@ -414,30 +1872,127 @@ impl Broker {
             BrokerBot {
                 id,
                 name: None,
+                team: None,
+                session: None,
                 address,
                 writer,
+                stop_reader,
+                connected_at: Local::now(),
+                last_seen: Local::now(),
+                last_log: None,
+                ping_sent: None,
+                latency: None,
+                clock: ClockSync::default(),
+                last_ack: None,
+                is_line_framed: false,
+                params: BTreeMap::new(),
+                param_setters: BTreeMap::new(),
+                signals: BTreeMap::new(),
             },
         );
         self.send_bot_result(id, sender, Ok(()));
     }
 
-    pub async fn bot_name_claim(&mut self, id: Ulid, name: String, sender: BrokerResultSender) {
-        self.ping_bots().await;
-
-        let result = if !is_name_valid(&name) {
-            Err(format!("invalid name '{}'", &name))
-        } else {
-            Ok(())
+    async fn deliver_queued_commands(&mut self, id: Ulid, name: &str) {
+        // The bot may be gone already, its commands then wait for the next one
+        let Some(bot) = self.bots.get_mut(&id) else {
+            return;
         };
-
-        let mut dead_client_ids = Vec::new();
-        if let Some(bot) = self.bots.get_mut(&id) {
-            bot.name = Some(name.clone());
-            let mut one_client_found = false;
-            for client in
-                self.clients
-                    .values_mut()
-                    .filter_map(|c| if c.has_name(&name) { Some(c) } else { None })
+        let queued_commands = match self.queued_commands.remove(name) {
+            Some(queued_commands) => queued_commands,
+            None => return,
+        };
+        let now = Local::now();
+        let mut messages = Vec::new();
+        let mut undelivered = Vec::new();
+        for queued in queued_commands {
+            if queued.expires_at < now {
+                messages.push(format!(
+                    "{}:{}:queued {} from {} expired\n",
+                    now, name, queued.command, queued.client
+                ));
+            } else if !undelivered.is_empty()
+                || bot
+                    .writer
+                    .write_all(&[queued.command.encode(), b'\n'])
+                    .await
+                    .is_err()
+            {
+                messages.push(format!(
+                    "{}:{}:queued {} from {} kept for later (bot unreachable)\n",
+                    now, name, queued.command, queued.client
+                ));
+                undelivered.push(queued);
+            } else {
+                messages.push(format!(
+                    "{}:{}:delivered {} queued by {} at {}\n",
+                    now,
+                    name,
+                    queued.command,
+                    queued.client,
+                    queued.queued_at.format(TIME_FORMAT)
+                ));
+            }
+        }
+        if !undelivered.is_empty() {
+            self.queued_commands.insert(name.to_string(), undelivered);
+            self.remove_dead_bot(id);
+        }
+        for message in messages {
+            print!("{}", &message);
+            self.write_event(EventKind::Commands, Some(name), None, &message)
+                .await;
+        }
+    }
+
+    pub async fn expire_queued_commands(&mut self) {
+        let now = Local::now();
+        let mut messages = Vec::new();
+        for (name, queued_commands) in self.queued_commands.iter_mut() {
+            queued_commands.retain(|queued| {
+                if queued.expires_at < now {
+                    messages.push((
+                        name.clone(),
+                        format!(
+                            "{}:{}:queued {} from {} expired\n",
+                            now, name, queued.command, queued.client
+                        ),
+                    ));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.queued_commands.retain(|_, queued| !queued.is_empty());
+        for (name, message) in messages {
+            print!("{}", &message);
+            self.write_event(EventKind::Commands, Some(&name), None, &message)
+                .await;
+        }
+    }
+
+    pub async fn bot_name_claim(&mut self, id: Ulid, name: String, sender: BrokerResultSender) {
+        // Lines still on their way from a bot that was let go of
+        if !self.bots.contains_key(&id) {
+            return;
+        }
+        self.ping_bots().await;
+
+        let result = if !is_name_valid(&name) {
+            Err(format!("invalid name '{}'", &name))
+        } else {
+            Ok(())
+        };
+
+        let mut dead_client_ids = Vec::new();
+        if let (Ok(()), Some(bot)) = (&result, self.bots.get_mut(&id)) {
+            bot.name = Some(name.clone());
+            let mut one_client_found = false;
+            for client in
+                self.clients
+                    .values_mut()
+                    .filter_map(|c| if c.has_name(&name) { Some(c) } else { None })
             {
                 one_client_found = true;
                 println!(
@@ -476,14 +2031,164 @@ impl Broker {
             self.remove_dead_client(id);
         }
 
+        if result.is_ok() {
+            let message = format!("{}:{}:connected", Local::now(), self.bot_info(id));
+            self.bot_hook(HookEvent::BotConnect, &name, &message);
+            if self.bots.get(&id).is_some_and(|bot| bot.is_line_framed) {
+                self.start_session(id, &name).await;
+            }
+            self.deliver_queued_commands(id, &name).await;
+        }
         self.send_bot_result(id, sender, result);
     }
 
+    async fn start_session(&mut self, id: Ulid, name: &str) {
+        let bots = &self.bots;
+        self.sessions.retain(|_, session| {
+            session.name != name || (bots.contains_key(&session.bot_id) && session.bot_id != id)
+        });
+
+        let token = Ulid::new().to_string();
+        let message = format!("{}SESSION:{}\n", BOT_LINE_PREFIX, &token);
+        let (is_dead, team) = match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.session = Some(token.clone());
+                (
+                    bot.writer.write_all(message.as_bytes()).await.is_err(),
+                    bot.team.clone(),
+                )
+            }
+            None => return,
+        };
+        self.sessions.insert(
+            token,
+            BotSession {
+                name: name.to_string(),
+                team,
+                bot_id: id,
+                disconnected_at: None,
+            },
+        );
+        if is_dead {
+            self.remove_dead_bot(id);
+        }
+    }
+
+    pub async fn bot_resume(&mut self, id: Ulid, token: String, sender: BrokerResultSender) {
+        if !self.bots.contains_key(&id) {
+            return;
+        }
+        let now = Local::now();
+        let (name, team, old_id, disconnected_at) = match self.sessions.get_mut(&token) {
+            Some(session) => {
+                let old_id = session.bot_id;
+                let disconnected_at = session.disconnected_at.take();
+                session.bot_id = id;
+                (
+                    session.name.clone(),
+                    session.team.clone(),
+                    old_id,
+                    disconnected_at,
+                )
+            }
+            None => {
+                let message = format!("{}SESSION_UNKNOWN\n", BOT_LINE_PREFIX);
+                let is_dead = match self.bots.get_mut(&id) {
+                    Some(bot) => bot.writer.write_all(message.as_bytes()).await.is_err(),
+                    None => false,
+                };
+                if is_dead {
+                    self.remove_dead_bot(id);
+                }
+                let error = format!("unknown session '{}'", token);
+                return self.send_bot_result(id, sender, Err(error));
+            }
+        };
+
+        let stale_bot = if old_id != id {
+            self.remove_bot(old_id, "replaced by a resumed connection")
+        } else {
+            None
+        };
+        let away_since = match stale_bot {
+            Some(stale_bot) => {
+                println!(
+                    "{}: evicting stale connection of bot {} at address {}",
+                    now, &name, stale_bot.address
+                );
+                stale_bot.last_seen
+            }
+            None => disconnected_at.unwrap_or(now),
+        };
+        match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.name = Some(name.clone());
+                bot.team = team;
+                bot.session = Some(token);
+                bot.is_line_framed = true;
+            }
+            None => return,
+        }
+
+        let away_for = (now - away_since).to_std().unwrap_or_default();
+        let message = format!(
+            "{}:{}:reconnected after {:.1}s\n",
+            now,
+            &name,
+            away_for.as_secs_f64()
+        );
+        print!("{}", &message);
+        self.bot_hook(HookEvent::BotConnect, &name, &message);
+        self.write_event(EventKind::Connections, Some(&name), None, &message)
+            .await;
+        self.deliver_queued_commands(id, &name).await;
+        self.send_bot_result(id, sender, Ok(()));
+    }
+
+    pub async fn bot_team_claim(&mut self, id: Ulid, team: String, sender: BrokerResultSender) {
+        if !self.bots.contains_key(&id) {
+            return;
+        }
+        if team.is_empty() || !is_name_valid(&team) {
+            let error = format!("invalid team '{}'", &team);
+            return self.send_bot_result(id, sender, Err(error));
+        }
+        let session = match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.team = Some(team.clone());
+                bot.session.clone()
+            }
+            None => return,
+        };
+        if let Some(session) = session.and_then(|token| self.sessions.get_mut(&token)) {
+            session.team = Some(team.clone());
+        }
+
+        let bot_info = self.bot_info(id);
+        let message = format!("{}:{}:joins team {}\n", Local::now(), &bot_info, &team);
+        print!("{}", &message);
+        self.write_event(EventKind::Connections, Some(&bot_info), None, &message)
+            .await;
+        self.send_bot_result(id, sender, Ok(()));
+    }
+
+    pub fn expire_sessions(&mut self) {
+        let now = Local::now();
+        let timeout = chrono::Duration::from_std(SESSION_TIMEOUT).unwrap_or_default();
+        self.sessions.retain(|_, session| {
+            session
+                .disconnected_at
+                .map(|disconnected_at| now - disconnected_at < timeout)
+                .unwrap_or(true)
+        });
+    }
+
     pub async fn join(
         &mut self,
         id: Ulid,
         address: SocketAddr,
         writer: OwnedWriteHalf,
+        stop_reader: oneshot::Sender<()>,
         sender: BrokerResultSender,
     ) {
         self.clients.insert(
@@ -491,9 +2196,15 @@ impl Broker {
             BrokerClient {
                 id,
                 name: None,
+                team: None,
                 is_referee: false,
+                is_spectator: false,
+                arena: None,
                 address,
                 writer,
+                stop_reader,
+                connected_at: Local::now(),
+                subscription: Subscription::default(),
             },
         );
         self.send_client_result(id, sender, Ok(()));
@@ -504,20 +2215,18 @@ impl Broker {
 
         let result = if !is_name_valid(&name) {
             Err(format!("invalid name '{}'", &name))
+        } else if self.is_spectator(id) {
+            Err("spectators cannot claim a name".to_string())
         } else {
             Ok(())
         };
 
         let mut messages = Vec::new();
         let mut dead_client_ids = BTreeSet::new();
-        if let Some(client) = self.clients.get_mut(&id) {
+        if let (Ok(()), Some(client)) = (&result, self.clients.get_mut(&id)) {
             client.name = Some(name.clone());
             let mut one_bot_found = false;
-            for bot in self
-                .bots
-                .values()
-                .filter_map(|b| if b.has_name(&name) { Some(b) } else { None })
-            {
+            for bot in self.bots.values().filter(|b| b.has_name(&name)) {
                 one_bot_found = true;
                 messages.push(format!(
                     "{}: client at address {} claims name {} and connects to bot at address {}\n",
@@ -555,12 +2264,68 @@ impl Broker {
             self.remove_dead_client(id);
         }
 
+        if let Err(err) = &result {
+            self.client_error(id, err.clone()).await;
+        }
         self.send_client_result(id, sender, result);
     }
 
+    pub async fn team_claim(&mut self, id: Ulid, team: String, sender: BrokerResultSender) {
+        let result = if team.is_empty() || !is_name_valid(&team) {
+            Err(format!("invalid team '{}'", &team))
+        } else if self.is_spectator(id) {
+            Err("spectators cannot join a team".to_string())
+        } else {
+            Ok(())
+        };
+        if let Err(err) = &result {
+            self.client_error(id, err.clone()).await;
+            return self.send_client_result(id, sender, result);
+        }
+
+        let address = match self.clients.get_mut(&id) {
+            Some(client) => {
+                client.team = Some(team.clone());
+                client.address
+            }
+            None => return,
+        };
+        let bots: Vec<String> = self
+            .bots
+            .values()
+            .filter(|b| b.in_team(&team))
+            .map(|b| {
+                b.name
+                    .as_ref()
+                    .cloned()
+                    .unwrap_or_else(|| b.address.to_string())
+            })
+            .collect();
+        let message = format!(
+            "{}: client at address {} joins team {} ({})\n",
+            Local::now(),
+            address,
+            &team,
+            if bots.is_empty() {
+                "no bots".to_string()
+            } else {
+                format!("bots {}", bots.join(", "))
+            }
+        );
+        print!("{}", &message);
+        self.write_to_client(id, &message).await;
+        self.send_client_result(id, sender, Ok(()));
+    }
+
     pub async fn referee_claim(&mut self, id: Ulid, sender: BrokerResultSender) {
         self.ping_clients().await;
 
+        if self.is_spectator(id) {
+            let error = "spectators cannot claim referee status".to_string();
+            self.client_error(id, error.clone()).await;
+            return self.send_client_result(id, sender, Err(error));
+        }
+
         let mut dead_client_ids = Vec::new();
         if let Some(client) = self.clients.get_mut(&id) {
             client.is_referee = true;
@@ -575,6 +2340,11 @@ impl Broker {
                 dead_client_ids.push(client.id);
             }
         }
+        self.audit(
+            Some(id),
+            AuditAction::RefereeClaim,
+            "claims referee status".to_string(),
+        );
         for id in dead_client_ids {
             self.remove_dead_client(id);
         }
@@ -582,18 +2352,75 @@ impl Broker {
         self.send_client_result(id, sender, Ok(()));
     }
 
-    pub async fn log(&mut self, id: Ulid, time: DateTime<Local>, message: String) {
-        let message = format!("{}:{}:{}\n", time, self.bot_info(id), message);
-        print!("{}", &message);
-        let mut dead_client_ids = Vec::new();
-        for client in self.clients.values_mut() {
-            if client.writer.write_all(message.as_bytes()).await.is_err() {
-                dead_client_ids.push(client.id);
+    pub async fn spectator_claim(&mut self, id: Ulid, sender: BrokerResultSender) {
+        let result = match self.clients.get_mut(&id) {
+            Some(client) if client.name.is_some() || client.is_referee => {
+                Err("named and referee clients cannot become spectators".to_string())
+            }
+            Some(client) => {
+                client.is_spectator = true;
+                Ok(client.address)
+            }
+            None => return,
+        };
+
+        match result {
+            Ok(address) => {
+                let message = format!(
+                    "{}: client at address {} joins as spectator\n",
+                    Local::now(),
+                    address
+                );
+                print!("{}", message);
+                self.write_to_client(id, &message).await;
+                self.send_client_result(id, sender, Ok(()));
+            }
+            Err(error) => {
+                self.client_error(id, error.clone()).await;
+                self.send_client_result(id, sender, Err(error));
             }
         }
-        for id in dead_client_ids {
-            self.remove_dead_client(id)
+    }
+
+    pub async fn log(
+        &mut self,
+        id: Ulid,
+        time: DateTime<Local>,
+        bot_ms: Option<u64>,
+        level: LogLevel,
+        message: String,
+    ) {
+        match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.last_log = Some(time);
+                bot.last_seen = time;
+            }
+            None => return,
         }
+        let bot_info = self.bot_info(id);
+        let message = match bot_ms {
+            Some(bot_ms) => {
+                let bot_time = self.epoch_time + chrono::Duration::milliseconds(bot_ms as i64);
+                format!(
+                    "@{} ({:+}ms) {}",
+                    bot_time.format("%H:%M:%S%.3f"),
+                    (time - bot_time).num_milliseconds(),
+                    message
+                )
+            }
+            None => message,
+        };
+        println!("{}:{}:{}:{}", time, &bot_info, level.encode(), &message);
+        let message = format!(
+            "{}{}:{}:{}:{}\n",
+            LOG_PREFIX,
+            level.encode(),
+            time,
+            &bot_info,
+            message
+        );
+        self.write_event(EventKind::Logs, Some(&bot_info), Some(level), &message)
+            .await;
     }
 
     pub async fn referee_command(
@@ -602,8 +2429,23 @@ impl Broker {
         time: DateTime<Local>,
         command: RefereeCommand,
     ) {
+        // Anyone can connect to the client port, so stopping a match takes a claim
+        let is_referee = self.clients.get(&id).map(|c| c.is_referee).unwrap_or(false);
+        if !is_referee {
+            let error = format!("only referees can send {}", command);
+            return self.client_error(id, error).await;
+        }
+        if let Some(arena) = self.clients.get(&id).and_then(|c| c.arena.clone()) {
+            return self.arena_command(id, time, arena, command).await;
+        }
+
         let message = format!("{}:{}:{}\n", time, self.client_info(id), command);
         print!("{}", &message);
+        self.audit(
+            Some(id),
+            AuditAction::RefereeCommand,
+            format!("{} to all bots", command),
+        );
         let encoded_command = [command.encode()];
 
         let mut dead_bot_ids = Vec::new();
@@ -616,102 +2458,2087 @@ impl Broker {
             self.remove_dead_bot(id)
         }
 
-        let mut dead_client_ids = Vec::new();
-        for client in self.clients.values_mut() {
-            if client.writer.write_all(message.as_bytes()).await.is_err() {
-                dead_client_ids.push(client.id);
-            }
-        }
-        for id in dead_client_ids {
-            self.remove_dead_client(id)
-        }
+        let event = match command {
+            RefereeCommand::Start => HookEvent::Start,
+            RefereeCommand::Stop => HookEvent::Stop,
+        };
+        self.hooks.trigger(event, HookPayload::new(event, &message));
+        self.write_event(EventKind::Referee, None, None, &message)
+            .await;
     }
 
-    pub async fn private_command(
+    /// Sends a referee command to the bots of the current match of an arena
+    async fn arena_command(
         &mut self,
         id: Ulid,
         time: DateTime<Local>,
-        command: PrivateCommand,
+        arena: String,
+        command: RefereeCommand,
     ) {
-        let encoded_command = [command.encode(), '\n' as u8];
-        let name = match self.clients.get(&id).and_then(|c| c.name.clone()) {
-            Some(name) => name,
-            None => {
-                println!(
-                    "{}: discarding command '{}' from unnamed client {}",
-                    Local::now(),
-                    command,
-                    self.client_info(id)
-                );
-                return;
-            }
+        let result = match self
+            .tournament
+            .arenas
+            .get_mut(&arena)
+            .and_then(|a| a.current.as_mut())
+        {
+            None => Err(format!("no match assigned in arena {}", &arena)),
+            Some(m) => match command {
+                RefereeCommand::Start if m.ended_at.is_some() => Err(format!(
+                    "match {} already ended, assign the next match",
+                    m.number
+                )),
+                RefereeCommand::Start => {
+                    m.started_at.get_or_insert(time);
+                    Ok((m.bots.clone(), m.describe(time), false))
+                }
+                RefereeCommand::Stop => {
+                    let is_ending = m.is_running();
+                    if is_ending {
+                        m.ended_at = Some(time);
+                    }
+                    Ok((m.bots.clone(), m.describe(time), is_ending))
+                }
+            },
+        };
+        let (bots, match_info, is_ending) = match result {
+            Ok(result) => result,
+            Err(error) => return self.client_error(id, error).await,
         };
+        self.save_state();
 
-        let mut messages = Vec::new();
+        self.audit(
+            Some(id),
+            AuditAction::RefereeCommand,
+            format!("{} in arena {} ({})", command, &arena, &match_info),
+        );
+        self.write_to_bots(&bots, &[command.encode()]).await;
+        let message = format!(
+            "{}:{}:{}:{} ({})\n",
+            time,
+            &arena,
+            self.client_info(id),
+            command,
+            match_info
+        );
+        print!("{}", &message);
+        let event = match command {
+            RefereeCommand::Start => HookEvent::Start,
+            RefereeCommand::Stop => HookEvent::Stop,
+        };
+        self.arena_hook(event, &arena, None, &message);
+        if is_ending {
+            self.arena_hook(HookEvent::MatchEnd, &arena, None, &message);
+        }
+        self.write_arena_event(EventKind::Referee, &arena, &message)
+            .await;
+    }
+
+    async fn write_to_bots(&mut self, names: &[String], message: &[u8]) {
         let mut dead_bot_ids = Vec::new();
-        let mut one_bot_found = false;
         for bot in self
             .bots
             .values_mut()
-            .filter_map(|b| if b.has_name(&name) { Some(b) } else { None })
+            .filter(|b| names.iter().any(|name| b.has_name(name)))
         {
-            one_bot_found = true;
-            if bot.writer.write_all(&encoded_command).await.is_err() {
-                messages.push(format!(
-                    "{}:{}:{} (bot unreachable)\n",
-                    time, &name, command
-                ));
+            if bot.writer.write_all(message).await.is_err() {
                 dead_bot_ids.push(bot.id);
-            } else {
-                messages.push(format!("{}:{}:{}\n", time, &name, command));
             }
         }
-        if !one_bot_found {
-            messages.push(format!(
-                "{}:{}:{} (bot not connected)\n",
-                time, &name, command
-            ));
-        }
         for id in dead_bot_ids {
             self.remove_dead_bot(id);
         }
+    }
 
-        let mut dead_client_ids = BTreeSet::new();
-        for message in messages {
-            print!("{}", &message);
-            for client in self.clients.values_mut() {
-                if client.writer.write_all(message.as_bytes()).await.is_err() {
-                    dead_client_ids.insert(client.id);
+    /// The arena of a referee, for the actions that only make sense within one
+    fn referee_arena(&self, id: Ulid) -> Result<String, String> {
+        match self.clients.get(&id) {
+            Some(client) if !client.is_referee => {
+                Err("only referees can manage matches".to_string())
+            }
+            Some(client) => client
+                .arena
+                .clone()
+                .ok_or_else(|| "attach to an arena to manage matches".to_string()),
+            None => Err(format!("unknown client {}", id)),
+        }
+    }
+
+    pub async fn arena_claim(&mut self, id: Ulid, arena: String, sender: BrokerResultSender) {
+        let result = if arena.is_empty() || !is_name_valid(&arena) {
+            Err(format!("invalid arena '{}'", &arena))
+        } else {
+            match self.clients.get_mut(&id) {
+                Some(client) if client.is_referee => {
+                    client.arena = Some(arena.clone());
+                    Ok(())
                 }
+                Some(_) => Err("only referees can attach to an arena".to_string()),
+                None => return,
             }
+        };
+        if let Err(err) = &result {
+            self.client_error(id, err.clone()).await;
+            return self.send_client_result(id, sender, result);
         }
-        for id in dead_client_ids {
-            self.remove_dead_client(id)
+
+        if !self.tournament.arenas.contains_key(&arena) {
+            self.tournament
+                .arenas
+                .insert(arena.clone(), Arena::new(arena.clone()));
+            self.save_state();
         }
+        self.audit(
+            Some(id),
+            AuditAction::ArenaClaim,
+            format!("attaches to arena {}", &arena),
+        );
+        let message = format!(
+            "{}: {} attaches to arena {}\n",
+            Local::now(),
+            self.client_info(id),
+            &arena
+        );
+        print!("{}", &message);
+        self.write_to_client(id, &message).await;
+        self.send_client_result(id, sender, Ok(()));
     }
 
-    pub async fn bot_leave(&mut self, id: Ulid) {
-        self.remove_dead_bot(id)
+    pub async fn new_match(
+        &mut self,
+        id: Ulid,
+        time: DateTime<Local>,
+        bots: Vec<String>,
+        duration: Option<Duration>,
+    ) {
+        let arena = match self.referee_arena(id) {
+            Ok(arena) => arena,
+            Err(err) => return self.client_error(id, err).await,
+        };
+        let unique_bots: BTreeSet<&String> = bots.iter().collect();
+        let is_bot_invalid = |bot: &String| bot.is_empty() || !is_name_valid(bot);
+        let error = if bots.is_empty() || bots.iter().any(is_bot_invalid) {
+            Some(format!("invalid match bots '{}'", bots.join(",")))
+        } else if unique_bots.len() != bots.len() {
+            Some("a bot cannot fight itself".to_string())
+        } else {
+            self.tournament
+                .arenas
+                .values()
+                .find_map(|other| match other.current.as_ref() {
+                    Some(m) if other.name == arena && m.is_running() => Some(format!(
+                        "match {} is still running, stop it first",
+                        m.number
+                    )),
+                    Some(m) if other.name != arena && m.ended_at.is_none() => bots
+                        .iter()
+                        .find(|bot| m.has_bot(bot))
+                        .map(|bot| format!("bot {} is assigned to arena {}", bot, &other.name)),
+                    _ => None,
+                })
+        };
+        if let Some(error) = error {
+            return self.client_error(id, error).await;
+        }
+
+        let match_info = match self.tournament.arenas.get_mut(&arena) {
+            Some(a) => a.next_match(bots, duration).describe(time),
+            None => return,
+        };
+        self.save_state();
+
+        self.audit(
+            Some(id),
+            AuditAction::Match,
+            format!("arena {}: next {}", &arena, &match_info),
+        );
+        let message = format!(
+            "{}:{}:{}:next {}\n",
+            time,
+            &arena,
+            self.client_info(id),
+            match_info
+        );
+        print!("{}", &message);
+        self.arena_hook(HookEvent::MatchNext, &arena, None, &message);
+        self.write_arena_event(EventKind::Referee, &arena, &message)
+            .await;
     }
 
-    pub async fn leave(&mut self, id: Ulid) {
-        self.remove_dead_client(id)
+    pub async fn score(&mut self, id: Ulid, time: DateTime<Local>, bot: String, points: i64) {
+        let arena = match self.referee_arena(id) {
+            Ok(arena) => arena,
+            Err(err) => return self.client_error(id, err).await,
+        };
+        let result = match self
+            .tournament
+            .arenas
+            .get_mut(&arena)
+            .and_then(|a| a.current.as_mut())
+        {
+            Some(m) if m.started_at.is_none() => {
+                Err(format!("match {} has not started yet", m.number))
+            }
+            Some(m) => match m.scores.get_mut(&bot) {
+                Some(score) => {
+                    *score += points;
+                    Ok(m.describe(time))
+                }
+                None => Err(format!("bot {} is not in match {}", &bot, m.number)),
+            },
+            None => Err(format!("no match assigned in arena {}", &arena)),
+        };
+        let match_info = match result {
+            Ok(match_info) => match_info,
+            Err(err) => return self.client_error(id, err).await,
+        };
+        self.save_state();
+
+        self.audit(
+            Some(id),
+            AuditAction::Score,
+            format!(
+                "arena {}: {} scores {} ({})",
+                &arena, &bot, points, &match_info
+            ),
+        );
+        let message = format!(
+            "{}:{}:{}:{} scores {} ({})\n",
+            time,
+            &arena,
+            self.client_info(id),
+            &bot,
+            points,
+            match_info
+        );
+        print!("{}", &message);
+        self.arena_hook(HookEvent::Score, &arena, Some(&bot), &message);
+        self.write_arena_event(EventKind::Referee, &arena, &message)
+            .await;
     }
-}
 
-async fn broker_bot_listener(listener: TcpListener, sender: BrokerActionSender) {
-    let broker_sender = sender;
-    loop {
-        match listener.accept().await {
-            Ok((stream, address)) => {
-                let (reader, writer) = stream.into_split();
-                let id = Ulid::new();
+    /// Stops the matches whose time is up
+    pub async fn check_match_timers(&mut self) {
+        let now = Local::now();
+        let mut expired = Vec::new();
+        for arena in self.tournament.arenas.values_mut() {
+            if let Some(m) = arena.current.as_mut() {
+                if let Some(deadline) = m.deadline().filter(|deadline| *deadline <= now) {
+                    m.ended_at = Some(deadline);
+                    expired.push((arena.name.clone(), m.bots.clone(), m.describe(now)));
+                }
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+        self.save_state();
+
+        let command = RefereeCommand::Stop;
+        for (arena, bots, match_info) in expired {
+            self.write_to_bots(&bots, &[command.encode()]).await;
+            let message = format!("{}:{}:time up, {} ({})\n", now, &arena, command, match_info);
+            print!("{}", &message);
+            self.arena_hook(HookEvent::TimeUp, &arena, None, &message);
+            self.arena_hook(HookEvent::MatchEnd, &arena, None, &message);
+            self.write_arena_event(EventKind::Referee, &arena, &message)
+                .await;
+        }
+    }
+
+    pub async fn arenas(&mut self, id: Ulid) {
+        let now = Local::now();
+        let mut lines = Vec::new();
+        for arena in self.tournament.arenas.values() {
+            let referees = self
+                .clients
+                .values()
+                .filter(|c| c.arena.as_ref() == Some(&arena.name))
+                .count();
+            lines.push(format!(
+                "arena {}: {} matches played, {} referees attached",
+                arena.name,
+                arena.played.len(),
+                referees
+            ));
+            lines.extend(
+                arena
+                    .played
+                    .iter()
+                    .chain(arena.current.iter())
+                    .map(|m| format!("  {}", m.describe(now))),
+            );
+            let standings: Vec<String> = arena
+                .standings()
+                .iter()
+                .map(|(bot, score)| format!("{} {}", bot, score))
+                .collect();
+            if !standings.is_empty() {
+                lines.push(format!("  standings: {}", standings.join(", ")));
+            }
+        }
+        if lines.is_empty() {
+            lines.push("no arenas".to_string());
+        }
+        self.write_info(id, lines).await;
+    }
+
+    /// Sends the bots and the arena of a client as a single JSON line, for dashboards
+    pub async fn board(&mut self, id: Ulid) {
+        let mut bots: Vec<BoardBot> = self
+            .bots
+            .values()
+            .filter_map(|bot| {
+                Some(BoardBot {
+                    name: bot.name.clone()?,
+                    team: bot.team.clone(),
+                    latency_ms: bot.latency.map(|l| l.as_secs_f64() * 1000.0),
+                    last_ack: bot.last_ack.map(|command| command.name().to_string()),
+                    disconnected_at: None,
+                })
+            })
+            .collect();
+        bots.extend(self.sessions.values().filter_map(|session| {
+            Some(BoardBot {
+                name: session.name.clone(),
+                team: session.team.clone(),
+                latency_ms: None,
+                last_ack: None,
+                disconnected_at: Some(session.disconnected_at?),
+            })
+        }));
+        bots.sort_by(|a, b| a.name.cmp(&b.name));
+        let arena = self
+            .clients
+            .get(&id)
+            .and_then(|client| client.arena.as_ref())
+            .and_then(|arena| self.tournament.arenas.get(arena))
+            .cloned();
+        let board = Board {
+            time: Local::now(),
+            bots,
+            arena,
+        };
+        match serde_json::to_string(&board) {
+            Ok(json) => {
+                self.write_to_client(id, &format!("{}{}\n", BOARD_PREFIX, json))
+                    .await
+            }
+            Err(err) => self.client_error(id, err.to_string()).await,
+        }
+    }
+
+    pub async fn private_command(
+        &mut self,
+        id: Ulid,
+        time: DateTime<Local>,
+        command: PrivateCommand,
+        queue_for: Option<Duration>,
+    ) {
+        if self.is_spectator(id) {
+            let error = format!("spectators cannot send {}", command);
+            return self.client_error(id, error).await;
+        }
+
+        let encoded_command = [command.encode(), b'\n'];
+        let (name, team) = match self.clients.get(&id) {
+            Some(client) => (client.name.clone(), client.team.clone()),
+            None => return,
+        };
+        let name = match (name, team) {
+            (Some(name), _) => name,
+            (None, Some(_)) if queue_for.is_none() => {
+                return self.team_command(id, time, None, command).await;
+            }
+            (None, _) => {
+                let error = format!("discarding {} from unnamed client", command);
+                return self.client_error(id, error).await;
+            }
+        };
+
+        let mut messages = Vec::new();
+        let mut dead_bot_ids = Vec::new();
+        let mut one_bot_found = false;
+        for bot in self
+            .bots
+            .values_mut()
+            .filter_map(|b| if b.has_name(&name) { Some(b) } else { None })
+        {
+            one_bot_found = true;
+            if bot.writer.write_all(&encoded_command).await.is_err() {
+                messages.push(format!(
+                    "{}:{}:{} (bot unreachable)\n",
+                    time, &name, command
+                ));
+                dead_bot_ids.push(bot.id);
+            } else {
+                messages.push(format!("{}:{}:{}\n", time, &name, command));
+            }
+        }
+        if !one_bot_found {
+            if let Some(queue_for) = queue_for {
+                let client_info = self.client_info(id);
+                let expires_at = time + chrono::Duration::from_std(queue_for).unwrap_or_default();
+                self.queued_commands
+                    .entry(name.clone())
+                    .or_default()
+                    .push(QueuedCommand {
+                        command,
+                        client: client_info,
+                        queued_at: time,
+                        expires_at,
+                    });
+                messages.push(format!(
+                    "{}:{}:{} (bot not connected, queued until {})\n",
+                    time,
+                    &name,
+                    command,
+                    expires_at.format(TIME_FORMAT)
+                ));
+            } else {
+                messages.push(format!(
+                    "{}:{}:{} (bot not connected)\n",
+                    time, &name, command
+                ));
+            }
+        }
+        for id in dead_bot_ids {
+            self.remove_dead_bot(id);
+        }
+
+        for message in messages {
+            print!("{}", &message);
+            self.write_event(EventKind::Commands, Some(&name), None, &message)
+                .await;
+        }
+    }
+
+    /// Sends a private command to one bot of the client team, or to all of them
+    pub async fn team_command(
+        &mut self,
+        id: Ulid,
+        time: DateTime<Local>,
+        bot: Option<String>,
+        command: PrivateCommand,
+    ) {
+        if self.is_spectator(id) {
+            let error = format!("spectators cannot send {}", command);
+            return self.client_error(id, error).await;
+        }
+
+        let team = match self.clients.get(&id).and_then(|c| c.team.clone()) {
+            Some(team) => team,
+            None => {
+                let error = format!("discarding {} from client without a team", command);
+                return self.client_error(id, error).await;
+            }
+        };
+
+        let encoded_command = [command.encode(), b'\n'];
+        let mut messages = Vec::new();
+        let mut dead_bot_ids = Vec::new();
+        for b in self.bots.values_mut().filter(|b| {
+            b.in_team(&team) && bot.as_ref().map(|name| b.has_name(name)).unwrap_or(true)
+        }) {
+            let bot_name = b
+                .name
+                .as_ref()
+                .cloned()
+                .unwrap_or_else(|| b.address.to_string());
+            if b.writer.write_all(&encoded_command).await.is_err() {
+                messages.push((
+                    bot_name.clone(),
+                    format!("{}:{}:{} (bot unreachable)\n", time, &bot_name, command),
+                ));
+                dead_bot_ids.push(b.id);
+            } else {
+                messages.push((
+                    bot_name.clone(),
+                    format!("{}:{}:{}\n", time, &bot_name, command),
+                ));
+            }
+        }
+        for id in dead_bot_ids {
+            self.remove_dead_bot(id);
+        }
+
+        if messages.is_empty() {
+            let error = match bot {
+                Some(bot) => format!("bot '{}' of team {} not connected", bot, &team),
+                None => format!("no bots of team {} connected", &team),
+            };
+            return self.client_error(id, error).await;
+        }
+        for (bot_name, message) in messages {
+            print!("{}", &message);
+            self.write_event(EventKind::Commands, Some(&bot_name), None, &message)
+                .await;
+        }
+    }
+
+    pub async fn announce(&mut self, id: Ulid, time: DateTime<Local>, text: String, to_bots: bool) {
+        let is_referee = self.clients.get(&id).map(|c| c.is_referee).unwrap_or(false);
+        if !is_referee {
+            let error = "only referees can make announcements".to_string();
+            return self.client_error(id, error).await;
+        }
+
+        let message = format!(
+            "{}{}:{}:{}\n",
+            ANNOUNCE_PREFIX,
+            time,
+            self.client_info(id),
+            text
+        );
+        print!("{}", &message);
+
+        if to_bots {
+            let encoded_announcement = format!("{}{}{}\n", BOT_LINE_PREFIX, ANNOUNCE_PREFIX, text);
+            let mut dead_bot_ids = Vec::new();
+            // Bots that read single command bytes would run the letters of the text
+            for bot in self.bots.values_mut().filter(|bot| bot.is_line_framed) {
+                if bot
+                    .writer
+                    .write_all(encoded_announcement.as_bytes())
+                    .await
+                    .is_err()
+                {
+                    dead_bot_ids.push(bot.id);
+                }
+            }
+            for id in dead_bot_ids {
+                self.remove_dead_bot(id)
+            }
+        }
+
+        self.hooks.trigger(
+            HookEvent::Announcement,
+            HookPayload::new(HookEvent::Announcement, &message),
+        );
+        self.write_event(EventKind::Announcements, None, None, &message)
+            .await;
+    }
+
+    pub async fn subscribe(&mut self, id: Ulid, subscription: String) {
+        let result = match self.clients.get_mut(&id) {
+            Some(client) => client
+                .subscription
+                .update(&subscription)
+                .map(|_| format!("subscribed to {}", client.subscription)),
+            None => return,
+        };
+        match result {
+            Ok(info) => self.write_info(id, vec![info]).await,
+            Err(error) => self.client_error(id, error).await,
+        }
+    }
+
+    /// Reports discarded lines in the log of the bot, or to the client that sent them
+    pub async fn dropped(&mut self, id: Ulid, time: DateTime<Local>, message: String) {
+        if self.bots.contains_key(&id) {
+            self.log(id, time, None, LogLevel::Warn, message).await;
+        } else if self.clients.contains_key(&id) {
+            self.client_error(id, message).await;
+        }
+    }
+
+    pub async fn client_error(&mut self, id: Ulid, message: String) {
+        let time = Local::now();
+        println!("{}:{}:error: {}", time, self.client_info(id), &message);
+        let message = format!("{}{}:{}\n", ERROR_PREFIX, time, message);
+        self.write_to_client(id, &message).await;
+    }
+
+    async fn write_to_client(&mut self, id: Ulid, message: &str) {
+        let is_dead = match self.clients.get_mut(&id) {
+            Some(client) => client.writer.write_all(message.as_bytes()).await.is_err(),
+            None => false,
+        };
+        if is_dead {
+            self.remove_dead_client(id);
+        }
+    }
+
+    async fn write_event(
+        &mut self,
+        kind: EventKind,
+        bot: Option<&str>,
+        level: Option<LogLevel>,
+        message: &str,
+    ) {
+        let team = bot.and_then(|bot| self.bot_team(bot));
+        let arena = bot
+            .and_then(|bot| self.tournament.bot_arena(bot))
+            .map(|arena| arena.name.clone());
+        let source = EventSource {
+            bot,
+            team: team.as_deref(),
+            arena: arena.as_deref(),
+        };
+        self.write_event_from(kind, &source, level, message).await;
+    }
+
+    async fn write_arena_event(&mut self, kind: EventKind, arena: &str, message: &str) {
+        let source = EventSource {
+            arena: Some(arena),
+            ..Default::default()
+        };
+        self.write_event_from(kind, &source, None, message).await;
+    }
+
+    async fn write_event_from(
+        &mut self,
+        kind: EventKind,
+        source: &EventSource<'_>,
+        level: Option<LogLevel>,
+        message: &str,
+    ) {
+        self.record_event(kind, source, level, message);
+        let mut dead_client_ids = Vec::new();
+        for client in self
+            .clients
+            .values_mut()
+            .filter(|c| c.subscription.accepts(kind, source, level))
+        {
+            if client.writer.write_all(message.as_bytes()).await.is_err() {
+                dead_client_ids.push(client.id);
+            }
+        }
+        for id in dead_client_ids {
+            self.remove_dead_client(id)
+        }
+    }
+
+    pub async fn bot_param(&mut self, id: Ulid, time: DateTime<Local>, param: BotParam) {
+        let bot_info = self.bot_info(id);
+        let (old_param, setter) = match self.bots.get_mut(&id) {
+            Some(bot) => (
+                {
+                    bot.last_seen = time;
+                    bot.params.insert(param.name.clone(), param.clone())
+                },
+                bot.param_setters.remove(&param.name),
+            ),
+            None => return,
+        };
+
+        match old_param {
+            Some(old_param) if old_param.value != param.value => {
+                let message = format!(
+                    "{}:{}:parameter {} changed from {} to {} by {}\n",
+                    time,
+                    bot_info,
+                    param.name,
+                    old_param.value,
+                    param.value,
+                    setter.unwrap_or_else(|| "bot".to_string())
+                );
+                print!("{}", &message);
+                self.write_event(EventKind::Params, Some(&bot_info), None, &message)
+                    .await;
+            }
+            Some(_) => {}
+            None => {
+                println!("{}:{}:advertises parameter {}", time, bot_info, param);
+            }
+        }
+    }
+
+    pub async fn bot_signal(&mut self, id: Ulid, time: DateTime<Local>, signal: TelemetrySignal) {
+        let bot_info = self.bot_info(id);
+        if let Some(bot) = self.bots.get_mut(&id) {
+            bot.last_seen = time;
+            println!("{}:{}:advertises {}", time, bot_info, signal);
+            bot.signals.insert(signal.id, signal);
+        }
+    }
+
+    pub async fn bot_telemetry(&mut self, id: Ulid, time: DateTime<Local>, frame: TelemetryFrame) {
+        let bot_info = self.bot_info(id);
+        let line = match self.bots.get_mut(&id) {
+            Some(bot) => {
+                bot.last_seen = time;
+                TelemetryLine::new(&bot_info, &frame, &bot.signals)
+            }
+            None => return,
+        };
+        let message = match line.encode() {
+            Ok(encoded) => format!("{}\n", encoded),
+            Err(err) => {
+                println!("{}:{}:cannot encode telemetry: {}", time, &bot_info, err);
+                return;
+            }
+        };
+        self.write_event(EventKind::Telemetry, Some(&bot_info), None, &message)
+            .await;
+    }
+
+    fn named_client(&self, id: Ulid) -> Result<String, String> {
+        self.clients
+            .get(&id)
+            .and_then(|c| c.name.clone())
+            .ok_or_else(|| "parameters are only available to named clients".to_string())
+    }
+
+    pub async fn param_list(&mut self, id: Ulid) {
+        let name = match self.named_client(id) {
+            Ok(name) => name,
+            Err(err) => return self.client_error(id, err).await,
+        };
+        let messages: Vec<String> = self
+            .bots
+            .values()
+            .filter(|b| b.has_name(&name))
+            .flat_map(|b| b.params.values())
+            .map(|p| format!("PARAM:{}:{}\n", &name, p.encode()))
+            .collect();
+        if messages.is_empty() {
+            let error = format!("no parameters advertised by bot '{}'", &name);
+            return self.client_error(id, error).await;
+        }
+        for message in messages {
+            self.write_to_client(id, &message).await;
+        }
+    }
+
+    pub async fn param_get(&mut self, id: Ulid, param_name: String) {
+        let name = match self.named_client(id) {
+            Ok(name) => name,
+            Err(err) => return self.client_error(id, err).await,
+        };
+        let messages: Vec<String> = self
+            .bots
+            .values()
+            .filter(|b| b.has_name(&name))
+            .filter_map(|b| b.params.get(&param_name))
+            .map(|p| format!("PARAM:{}:{}\n", &name, p.encode()))
+            .collect();
+        if messages.is_empty() {
+            let error = format!("bot '{}' has no parameter {}", &name, &param_name);
+            return self.client_error(id, error).await;
+        }
+        for message in messages {
+            self.write_to_client(id, &message).await;
+        }
+    }
+
+    pub async fn param_set(
+        &mut self,
+        id: Ulid,
+        time: DateTime<Local>,
+        param_name: String,
+        value: String,
+    ) {
+        let name = match self.named_client(id) {
+            Ok(name) => name,
+            Err(err) => return self.client_error(id, err).await,
+        };
+        let client_info = self.client_info(id);
+
+        let mut errors = Vec::new();
+        let mut messages = Vec::new();
+        let mut audits = Vec::new();
+        let mut dead_bot_ids = Vec::new();
+        for bot in self.bots.values_mut().filter(|b| b.has_name(&name)) {
+            let param = match bot.params.get(&param_name) {
+                Some(param) => param,
+                None => {
+                    errors.push(format!("bot '{}' has no parameter {}", &name, &param_name));
+                    continue;
+                }
+            };
+            let new_value = match param.check(&value) {
+                Ok(new_value) => new_value,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            let old_value = param.value;
+            let encoded_command = format!("{}SET:{}={}\n", BOT_LINE_PREFIX, &param_name, new_value);
+            if bot
+                .writer
+                .write_all(encoded_command.as_bytes())
+                .await
+                .is_err()
+            {
+                dead_bot_ids.push(bot.id);
+                errors.push(format!("bot '{}' unreachable", &name));
+            } else {
+                bot.param_setters
+                    .insert(param_name.clone(), client_info.clone());
+                audits.push(format!(
+                    "bot {} parameter {} from {} to {}",
+                    &name, &param_name, old_value, new_value
+                ));
+                messages.push(format!(
+                    "{}:{}:{} requests parameter {} change from {} to {}\n",
+                    time, &name, &client_info, &param_name, old_value, new_value
+                ));
+            }
+        }
+        if errors.is_empty() && messages.is_empty() {
+            errors.push(format!("bot '{}' not connected", &name));
+        }
+        for detail in audits {
+            self.audit(Some(id), AuditAction::ParamSet, detail);
+        }
+        for id in dead_bot_ids {
+            self.remove_dead_bot(id);
+        }
+
+        for message in messages {
+            print!("{}", &message);
+            self.write_to_client(id, &message).await;
+        }
+        for error in errors {
+            self.client_error(id, error).await;
+        }
+    }
+
+    /// Sends STOP to every bot and ends the running matches
+    pub async fn emergency_stop(&mut self) {
+        let now = Local::now();
+        let command = RefereeCommand::Stop;
+        let mut dead_bot_ids = Vec::new();
+        for bot in self.bots.values_mut() {
+            if bot.writer.write_all(&[command.encode()]).await.is_err() {
+                dead_bot_ids.push(bot.id);
+            }
+        }
+        for id in dead_bot_ids {
+            self.remove_dead_bot(id);
+        }
+
+        let mut ended_arenas = Vec::new();
+        for arena in self.tournament.arenas.values_mut() {
+            if let Some(m) = arena.current.as_mut().filter(|m| m.is_running()) {
+                m.ended_at = Some(now);
+                ended_arenas.push(arena.name.clone());
+            }
+        }
+        if !ended_arenas.is_empty() {
+            self.save_state();
+        }
+
+        self.audit(
+            None,
+            AuditAction::EmergencyStop,
+            format!("{} to all bots", command),
+        );
+        let message = format!("{}:operator:{} to all bots\n", now, command);
+        print!("{}", &message);
+        self.hooks.trigger(
+            HookEvent::EmergencyStop,
+            HookPayload::new(HookEvent::EmergencyStop, &message),
+        );
+        for arena in ended_arenas {
+            self.arena_hook(HookEvent::MatchEnd, &arena, None, &message);
+        }
+        self.write_event(EventKind::Referee, None, None, &message)
+            .await;
+    }
+
+    /// Runs a command typed on the broker console
+    pub async fn console(&mut self, line: String) {
+        let line = line.trim();
+        let (command, rest) = line
+            .split_once(' ')
+            .map(|(command, rest)| (command, rest.trim()))
+            .unwrap_or((line, ""));
+        let result = match command {
+            "" => Ok(()),
+            "help" => {
+                println!("{}", CONSOLE_HELP);
+                Ok(())
+            }
+            "list" => {
+                self.console_list();
+                Ok(())
+            }
+            "kick" => self.console_kick(rest).await,
+            "stop" => {
+                self.emergency_stop().await;
+                Ok(())
+            }
+            "rename" => match rest.split_once('=') {
+                Some((target, name)) => self.console_rename(target, Some(name.trim())).await,
+                None => Err("usage: rename <bot|client> <name or address>=<new name>".to_string()),
+            },
+            "unname" => self.console_rename(rest, None).await,
+            "record" => self.console_record(rest),
+            "dump" => {
+                self.console_dump();
+                Ok(())
+            }
+            "quit" => {
+                self.is_shutting_down = true;
+                Ok(())
+            }
+            _ => Err(format!("unknown command '{}', type 'help'", command)),
+        };
+        if let Err(err) = result {
+            println!("console: {}", err);
+        }
+    }
+
+    fn console_target(&self, text: &str) -> Result<ConsoleTarget, String> {
+        let (kind, target) = text
+            .trim()
+            .split_once(' ')
+            .map(|(kind, target)| (kind, target.trim()))
+            .ok_or("expected 'bot <name or address>' or 'client <name or address>'")?;
+        let ids: Vec<Ulid> = match kind {
+            "bot" => self
+                .bots
+                .values()
+                .filter(|b| b.has_name(target) || b.address.to_string() == target)
+                .map(|b| b.id)
+                .collect(),
+            "client" => self
+                .clients
+                .values()
+                .filter(|c| c.has_name(target) || c.address.to_string() == target)
+                .map(|c| c.id)
+                .collect(),
+            _ => return Err(format!("invalid connection kind '{}'", kind)),
+        };
+        match ids.as_slice() {
+            [id] if kind == "bot" => Ok(ConsoleTarget::Bot(*id)),
+            [id] => Ok(ConsoleTarget::Client(*id)),
+            [] => Err(format!("no {} matches '{}'", kind, target)),
+            _ => Err(format!(
+                "{} {}s match '{}', use the address",
+                ids.len(),
+                kind,
+                target
+            )),
+        }
+    }
+
+    fn console_list(&self) {
+        for bot in self.bots.values() {
+            println!("{}", bot.describe());
+        }
+        for client in self.clients.values() {
+            println!("{}", client.describe());
+        }
+        println!("{}", self.referee_info());
+    }
+
+    async fn console_kick(&mut self, target: &str) -> Result<(), String> {
+        match self.console_target(target)? {
+            ConsoleTarget::Bot(id) => {
+                let bot_info = self.bot_info(id);
+                let message = format!("{}:{}:kicked by the operator\n", Local::now(), &bot_info);
+                print!("{}", &message);
+                self.audit(None, AuditAction::Kick, format!("bot {}", &bot_info));
+                self.write_event(EventKind::Connections, Some(&bot_info), None, &message)
+                    .await;
+                if let Some(mut bot) = self.remove_bot(id, "kicked by the operator") {
+                    if let Some(token) = bot.session.as_ref() {
+                        self.sessions.remove(token);
+                    }
+                    bot.writer.shutdown().await.ok();
+                    bot.stop_reader.send(()).ok();
+                }
+            }
+            ConsoleTarget::Client(id) => {
+                let client_info = self.client_info(id);
+                println!(
+                    "{}: client {} kicked by the operator",
+                    Local::now(),
+                    &client_info
+                );
+                self.audit(None, AuditAction::Kick, format!("client {}", client_info));
+                self.client_error(id, "kicked by the broker operator".to_string())
+                    .await;
+                if let Some(mut client) = self.clients.remove(&id) {
+                    client.writer.shutdown().await.ok();
+                    client.stop_reader.send(()).ok();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn console_rename(&mut self, target: &str, name: Option<&str>) -> Result<(), String> {
+        if let Some(name) = name.filter(|name| name.is_empty() || !is_name_valid(name)) {
+            return Err(format!("invalid name '{}'", name));
+        }
+        match self.console_target(target)? {
+            ConsoleTarget::Bot(id) => {
+                let old_info = self.bot_info(id);
+                let session = match self.bots.get_mut(&id) {
+                    Some(bot) => {
+                        bot.name = name.map(str::to_string);
+                        match name {
+                            Some(_) => bot.session.clone(),
+                            None => bot.session.take(),
+                        }
+                    }
+                    None => return Ok(()),
+                };
+                if let Some(token) = session {
+                    match name {
+                        Some(name) => {
+                            if let Some(session) = self.sessions.get_mut(&token) {
+                                session.name = name.to_string();
+                            }
+                        }
+                        None => {
+                            self.sessions.remove(&token);
+                        }
+                    }
+                }
+                let bot_info = self.bot_info(id);
+                self.audit(
+                    None,
+                    AuditAction::Rename,
+                    format!("bot {} to {}", &old_info, &bot_info),
+                );
+                let message = format!(
+                    "{}:{}:renamed by the operator to {}\n",
+                    Local::now(),
+                    &old_info,
+                    &bot_info
+                );
+                print!("{}", &message);
+                self.write_event(EventKind::Connections, Some(&bot_info), None, &message)
+                    .await;
+                if let Some(name) = name {
+                    self.deliver_queued_commands(id, name).await;
+                }
+            }
+            ConsoleTarget::Client(id) => {
+                let old_info = self.client_info(id);
+                match self.clients.get_mut(&id) {
+                    Some(client) if client.is_spectator && name.is_some() => {
+                        return Err("spectators cannot have a name".to_string());
+                    }
+                    Some(client) => client.name = name.map(str::to_string),
+                    None => return Ok(()),
+                }
+                let client_info = self.client_info(id);
+                self.audit(
+                    None,
+                    AuditAction::Rename,
+                    format!("client {} to {}", &old_info, &client_info),
+                );
+                let message = format!(
+                    "{}: client {} renamed by the operator to {}\n",
+                    Local::now(),
+                    &old_info,
+                    &client_info
+                );
+                print!("{}", &message);
+                self.write_to_client(id, &message).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn console_record(&mut self, path: &str) -> Result<(), String> {
+        if !path.is_empty() {
+            return self
+                .start_recording(PathBuf::from(path))
+                .map_err(|err| err.to_string());
+        }
+        if self.recorder.is_some() {
+            self.stop_recording();
+            return Ok(());
+        }
+        let path = self
+            .recording_path
+            .clone()
+            .unwrap_or_else(default_recording_path);
+        self.start_recording(path).map_err(|err| err.to_string())
+    }
+
+    fn console_dump(&self) {
+        println!("bots:");
+        for bot in self.bots.values() {
+            println!(
+                "  {} {} session {}",
+                bot.id,
+                bot.describe(),
+                bot.session.as_deref().unwrap_or("none")
+            );
+            for param in bot.params.values() {
+                println!("    {}", param);
+            }
+            for signal in bot.signals.values() {
+                println!("    {}", signal);
+            }
+        }
+        println!("clients:");
+        for client in self.clients.values() {
+            println!(
+                "  {} {} subscribed to {}",
+                client.id,
+                client.describe(),
+                client.subscription
+            );
+        }
+        println!("sessions:");
+        for (token, session) in self.sessions.iter() {
+            println!(
+                "  {} bot {}{} {}",
+                token,
+                session.name,
+                session
+                    .team
+                    .as_ref()
+                    .map(|t| format!(" (team {})", t))
+                    .unwrap_or_default(),
+                session
+                    .disconnected_at
+                    .map(|t| format!("disconnected {}", t.format(TIME_FORMAT)))
+                    .unwrap_or_else(|| format!("connected as {}", session.bot_id))
+            );
+        }
+        println!("queued commands:");
+        for (name, queued_commands) in self.queued_commands.iter() {
+            for queued in queued_commands {
+                println!(
+                    "  {} {} from {} until {}",
+                    name,
+                    queued.command,
+                    queued.client,
+                    queued.expires_at.format(TIME_FORMAT)
+                );
+            }
+        }
+        println!(
+            "recording: {}",
+            self.recorder
+                .as_ref()
+                .map(|r| r.path().display().to_string())
+                .unwrap_or_else(|| "off".to_string())
+        );
+        println!(
+            "state file: {}",
+            self.state_file
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|| "none".to_string())
+        );
+        match serde_json::to_string_pretty(&self.tournament) {
+            Ok(tournament) => println!("tournament: {}", tournament),
+            Err(err) => println!("tournament: {}", err),
+        }
+    }
+
+    /// Stops every bot, waits for their acknowledgements and tells the clients
+    pub async fn shutdown(&mut self, receiver: &mut BrokerActionReceiver) {
+        println!("{}: shutting down", Local::now());
+        self.emergency_stop().await;
+        self.stop_acks_pending = self.bots.keys().copied().collect();
+        let bot_count = self.stop_acks_pending.len();
+
+        let timeout = tokio::time::sleep(SHUTDOWN_ACK_TIMEOUT);
+        tokio::pin!(timeout);
+        while !self.stop_acks_pending.is_empty() {
+            select! {
+                action = receiver.recv() => match action {
+                    Some(action) => self.handle(action).await,
+                    None => break,
+                },
+                _ = &mut timeout => break,
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+
+        let acked_count = bot_count - self.stop_acks_pending.len();
+        let message = format!(
+            "{}{}:broker:shutting down, {} of {} bots acknowledged STOP\n",
+            ANNOUNCE_PREFIX,
+            Local::now(),
+            acked_count,
+            bot_count
+        );
+        print!("{}", &message);
+        for client in self.clients.values_mut() {
+            client.writer.write_all(message.as_bytes()).await.ok();
+        }
+        self.stop_recording();
+        self.save_state();
+    }
+
+    pub async fn bot_leave(&mut self, id: Ulid) {
+        self.remove_dead_bot(id)
+    }
+
+    pub async fn leave(&mut self, id: Ulid) {
+        self.remove_dead_client(id)
+    }
+
+    pub async fn handle(&mut self, action: BrokerAction) {
+        match action {
+            BrokerAction::BotJoin {
+                id,
+                address,
+                writer,
+                stop_reader,
+                sender,
+            } => {
+                self.bot_join(id, address, writer, stop_reader, sender)
+                    .await;
+            }
+            BrokerAction::BotNameClaim { id, name, sender } => {
+                self.bot_name_claim(id, name, sender).await;
+            }
+            BrokerAction::BotResume { id, token, sender } => {
+                self.bot_resume(id, token, sender).await;
+            }
+            BrokerAction::BotTeamClaim { id, team, sender } => {
+                self.bot_team_claim(id, team, sender).await;
+            }
+            BrokerAction::Join {
+                id,
+                address,
+                writer,
+                stop_reader,
+                sender,
+            } => {
+                self.join(id, address, writer, stop_reader, sender).await;
+            }
+            BrokerAction::NameClaim { id, name, sender } => {
+                self.name_claim(id, name, sender).await;
+            }
+            BrokerAction::TeamClaim { id, team, sender } => {
+                self.team_claim(id, team, sender).await;
+            }
+            BrokerAction::RefereeClaim { id, sender } => {
+                self.referee_claim(id, sender).await;
+            }
+            BrokerAction::ArenaClaim { id, arena, sender } => {
+                self.arena_claim(id, arena, sender).await;
+            }
+            BrokerAction::SpectatorClaim { id, sender } => {
+                self.spectator_claim(id, sender).await;
+            }
+            BrokerAction::Subscribe { id, subscription } => {
+                self.subscribe(id, subscription).await;
+            }
+            BrokerAction::Log {
+                id,
+                time,
+                bot_ms,
+                level,
+                message,
+            } => {
+                self.log(id, time, bot_ms, level, message).await;
+            }
+            BrokerAction::BotSync {
+                id,
+                received,
+                bot_ms,
+            } => {
+                self.bot_sync(id, received, bot_ms).await;
+            }
+            BrokerAction::RefereeCommand { id, time, command } => {
+                self.referee_command(id, time, command).await;
+            }
+            BrokerAction::PrivateCommand {
+                id,
+                time,
+                command,
+                queue_for,
+            } => {
+                self.private_command(id, time, command, queue_for).await;
+            }
+            BrokerAction::TeamCommand {
+                id,
+                time,
+                bot,
+                command,
+            } => {
+                self.team_command(id, time, bot, command).await;
+            }
+            BrokerAction::NewMatch {
+                id,
+                time,
+                bots,
+                duration,
+            } => {
+                self.new_match(id, time, bots, duration).await;
+            }
+            BrokerAction::Score {
+                id,
+                time,
+                bot,
+                points,
+            } => {
+                self.score(id, time, bot, points).await;
+            }
+            BrokerAction::Announce {
+                id,
+                time,
+                text,
+                to_bots,
+            } => {
+                self.announce(id, time, text, to_bots).await;
+            }
+            BrokerAction::ClientError { id, message } => {
+                self.client_error(id, message).await;
+            }
+            BrokerAction::BotPong { id, time } => {
+                self.bot_pong(id, time).await;
+            }
+            BrokerAction::BotLines { id } => {
+                self.bot_lines(id).await;
+            }
+            BrokerAction::BotAck { id, time, command } => {
+                self.bot_ack(id, time, command).await;
+            }
+            BrokerAction::List { id } => {
+                self.list(id).await;
+            }
+            BrokerAction::Status { id, name } => {
+                self.status(id, name).await;
+            }
+            BrokerAction::Whoami { id } => {
+                self.whoami(id).await;
+            }
+            BrokerAction::Arenas { id } => {
+                self.arenas(id).await;
+            }
+            BrokerAction::Board { id } => {
+                self.board(id).await;
+            }
+            BrokerAction::BotParam { id, time, param } => {
+                self.bot_param(id, time, param).await;
+            }
+            BrokerAction::BotSignal { id, time, signal } => {
+                self.bot_signal(id, time, signal).await;
+            }
+            BrokerAction::BotTelemetry { id, time, frame } => {
+                self.bot_telemetry(id, time, frame).await;
+            }
+            BrokerAction::ParamList { id } => {
+                self.param_list(id).await;
+            }
+            BrokerAction::ParamGet { id, name } => {
+                self.param_get(id, name).await;
+            }
+            BrokerAction::ParamSet {
+                id,
+                time,
+                name,
+                value,
+            } => {
+                self.param_set(id, time, name, value).await;
+            }
+            BrokerAction::Dropped { id, time, message } => {
+                self.dropped(id, time, message).await;
+            }
+            BrokerAction::BotLeave { id } => {
+                self.bot_leave(id).await;
+            }
+            BrokerAction::Leave { id } => {
+                self.leave(id).await;
+            }
+            BrokerAction::Console { line } => {
+                self.console(line).await;
+            }
+        }
+    }
+}
+
+/// Splits the `@<broker ms> ` stamp of a line produced at a known broker time
+fn split_stamp(line: &str) -> (Option<u64>, &str) {
+    line.strip_prefix('@')
+        .and_then(|rest| rest.split_once(' '))
+        .and_then(|(stamp, rest)| Some((Some(stamp.parse().ok()?), rest)))
+        .unwrap_or((None, line))
+}
+
+async fn broker_bot_line(id: Ulid, line: String, sender: &BrokerActionSender) -> bool {
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    if let Some(name) = line.strip_prefix("NAME:") {
+        let name = name.to_string();
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::BotNameClaim {
+                id,
+                name,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: bot name claim ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if let Some(token) = line.strip_prefix("RESUME:") {
+        let token = token.to_string();
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::BotResume {
+                id,
+                token,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: bot session resume ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if let Some(team) = line.strip_prefix("TEAM:") {
+        let team = team.to_string();
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::BotTeamClaim {
+                id,
+                team,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: bot team claim ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if let Some(ack) = line.strip_prefix("ACK:") {
+        let command = match ack.as_bytes() {
+            [byte] => RefereeCommand::decode(*byte),
+            _ => None,
+        };
+        if let Some(command) = command {
+            sender
+                .send(BrokerAction::BotAck {
+                    id,
+                    time: Local::now(),
+                    command,
+                })
+                .await
+                .ok();
+        } else {
+            println!("{}: invalid bot acknowledgement '{}'", Local::now(), ack);
+        }
+    } else if let Some(bot_ms) = line.strip_prefix("SYNC:") {
+        match bot_ms.parse() {
+            Ok(bot_ms) => {
+                sender
+                    .send(BrokerAction::BotSync {
+                        id,
+                        received: Instant::now(),
+                        bot_ms,
+                    })
+                    .await
+                    .ok();
+            }
+            Err(_) => println!("{}: invalid bot sync '{}'", Local::now(), bot_ms),
+        }
+    } else if line == "PONG" {
+        sender
+            .send(BrokerAction::BotPong {
+                id,
+                time: Instant::now(),
+            })
+            .await
+            .ok();
+    } else if let Some(param) = line.strip_prefix("PARAM:") {
+        if let Some(param) = BotParam::decode(param) {
+            sender
+                .send(BrokerAction::BotParam {
+                    id,
+                    time: Local::now(),
+                    param,
+                })
+                .await
+                .ok();
+        } else {
+            println!("{}: invalid bot parameter '{}'", Local::now(), param);
+        }
+    } else if line == "LINES" {
+        sender.send(BrokerAction::BotLines { id }).await.ok();
+    } else if let Some(signal) = line.strip_prefix("SIGNAL:") {
+        if let Some(signal) = TelemetrySignal::decode(signal) {
+            sender
+                .send(BrokerAction::BotSignal {
+                    id,
+                    time: Local::now(),
+                    signal,
+                })
+                .await
+                .ok();
+        } else {
+            println!("{}: invalid bot signal '{}'", Local::now(), signal);
+        }
+    } else {
+        let (bot_ms, line) = split_stamp(line);
+        let (level, message) = LogLevel::split_tag(line);
+        sender
+            .send(BrokerAction::Log {
+                id,
+                time: Local::now(),
+                bot_ms,
+                level: level.unwrap_or(LogLevel::Info),
+                message: message.to_string(),
+            })
+            .await
+            .ok();
+    }
+    true
+}
+
+async fn broker_bot_telemetry(id: Ulid, payload: Vec<u8>, sender: &BrokerActionSender) -> bool {
+    match TelemetryFrame::decode(&payload) {
+        Some(frame) => {
+            sender
+                .send(BrokerAction::BotTelemetry {
+                    id,
+                    time: Local::now(),
+                    frame,
+                })
+                .await
+                .ok();
+        }
+        None => println!(
+            "{}: invalid telemetry frame of {} bytes",
+            Local::now(),
+            payload.len()
+        ),
+    }
+    true
+}
+
+/// What a bot sends, either a text line or the payload of a binary telemetry frame
+enum BotMessage {
+    Line(String),
+    Telemetry(Vec<u8>),
+}
+
+async fn read_bot_message<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<BotMessage>> {
+    let first = match reader.fill_buf().await?.first() {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+    if first == TELEMETRY_MARKER {
+        reader.consume(1);
+        let length = reader.read_u16_le().await?;
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload).await?;
+        return Ok(Some(BotMessage::Telemetry(payload)));
+    }
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line).await?;
+    let line = String::from_utf8_lossy(&line);
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    Ok(Some(BotMessage::Line(line.to_string())))
+}
+
+/// Lines bots send to keep their connection working, which are never rate limited
+fn is_bot_control_line(line: &str) -> bool {
+    line == "PONG"
+        || line == "LINES"
+        || ["NAME:", "RESUME:", "TEAM:", "ACK:", "SYNC:"]
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+}
+
+async fn send_dropped(id: Ulid, message: String, sender: &BrokerActionSender) {
+    sender
+        .send(BrokerAction::Dropped {
+            id,
+            time: Local::now(),
+            message,
+        })
+        .await
+        .ok();
+}
+
+/// Passes what a bot sends to the broker, within its rate limit, until it disconnects
+async fn broker_bot_reader(
+    id: Ulid,
+    mut reader: BufReader<OwnedReadHalf>,
+    sender: &BrokerActionSender,
+    limit: RateLimit,
+) {
+    let mut limiter = Limiter::new(limit);
+    loop {
+        // Without new lines the end of a flood would go unreported, so the read is
+        // interrupted for markers, but kept going to not lose a partial message
+        let read = read_bot_message(&mut reader);
+        tokio::pin!(read);
+        let result = loop {
+            if !limiter.is_dropping() {
+                break (&mut read).await;
+            }
+            match timeout(MARKER_INTERVAL, &mut read).await {
+                Ok(result) => break result,
+                Err(_) => {
+                    if let Some(marker) = limiter.marker(true) {
+                        send_dropped(id, marker, sender).await;
+                    }
+                }
+            }
+        };
+        let message = match result {
+            Ok(Some(message)) => message,
+            _ => break,
+        };
+        let admission = match &message {
+            BotMessage::Line(line) if is_bot_control_line(line) => Admission::Accept,
+            BotMessage::Line(line) => {
+                let (level, _) = LogLevel::split_tag(split_stamp(line).1);
+                limiter.admit(Some(level.unwrap_or(LogLevel::Info).encode()), Some(line))
+            }
+            BotMessage::Telemetry(_) => limiter.admit(Some("telemetry"), None),
+        };
+        match admission {
+            Admission::Accept => {
+                if let Some(marker) = limiter.marker(true) {
+                    send_dropped(id, marker, sender).await;
+                }
+            }
+            Admission::Drop => {
+                if let Some(marker) = limiter.marker(false) {
+                    send_dropped(id, marker, sender).await;
+                }
+                continue;
+            }
+            Admission::Disconnect => {
+                send_dropped(id, limiter.disconnect_message(), sender).await;
+                break;
+            }
+        }
+        let is_connected = match message {
+            BotMessage::Line(line) => broker_bot_line(id, line, sender).await,
+            BotMessage::Telemetry(payload) => broker_bot_telemetry(id, payload, sender).await,
+        };
+        if !is_connected {
+            break;
+        }
+    }
+}
+
+async fn broker_bot_listener(listener: TcpListener, sender: BrokerActionSender, limit: RateLimit) {
+    let broker_sender = sender;
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                let (reader, writer) = stream.into_split();
+                let id = Ulid::new();
+                let (stop_reader, reader_stopped) = oneshot::channel();
+                let (sender, receiver) = oneshot::channel();
+                if broker_sender
+                    .send(BrokerAction::BotJoin {
+                        id,
+                        address,
+                        writer,
+                        stop_reader,
+                        sender,
+                    })
+                    .await
+                    .is_ok()
+                {
+                    receiver.await.ok();
+
+                    let bot_broker_sender = broker_sender.clone();
+                    let buf_reader = BufReader::new(reader);
+                    spawn(async move {
+                        // The broker lets go of the bot when kicking it, or when it is gone
+                        select! {
+                            _ = broker_bot_reader(id, buf_reader, &bot_broker_sender, limit) => {}
+                            _ = reader_stopped => {}
+                        }
+                        bot_broker_sender
+                            .send(BrokerAction::BotLeave { id })
+                            .await
+                            .ok();
+                    });
+                }
+            }
+            Err(err) => {
+                println!("{}: error listening on log port: {}", Local::now(), err);
+            }
+        }
+    }
+}
+
+async fn broker_cmd_line(id: Ulid, line: String, sender: &BrokerActionSender) -> bool {
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    if let Some(name) = line.strip_prefix("NAME:") {
+        let name = name.to_string();
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::NameClaim {
+                id,
+                name,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: client name claim ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if let Some(team) = line.strip_prefix("TEAM:") {
+        let team = team.to_string();
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::TeamClaim {
+                id,
+                team,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: client team claim ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if line == "REFEREE" {
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::RefereeClaim {
+                id,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: client referee claim ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if let Some(arena) = line.strip_prefix("ARENA:") {
+        let arena = arena.to_string();
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::ArenaClaim {
+                id,
+                arena,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if let Ok(result) = receiver.await {
+            if let Err(err) = result {
+                println!("{}: client arena claim ignored: {}", Local::now(), err);
+            }
+        } else {
+            return false;
+        }
+    } else if let Some(assignment) = line.strip_prefix("MATCH:") {
+        let (bots, seconds) = match assignment.split_once(':') {
+            Some((bots, seconds)) => (bots, Some(seconds)),
+            None => (assignment, None),
+        };
+        let duration = match seconds.map(|s| s.parse::<u64>()) {
+            Some(Ok(seconds)) => Some(Some(Duration::from_secs(seconds))),
+            Some(Err(_)) => None,
+            None => Some(None),
+        };
+        if let Some(duration) = duration {
+            let bots = bots.split(',').map(|b| b.trim().to_string()).collect();
+            sender
+                .send(BrokerAction::NewMatch {
+                    id,
+                    time: Local::now(),
+                    bots,
+                    duration,
+                })
+                .await
+                .ok();
+        } else {
+            let message = format!("invalid match '{}'", assignment);
+            sender
+                .send(BrokerAction::ClientError { id, message })
+                .await
+                .ok();
+        }
+    } else if let Some(score) = line.strip_prefix("SCORE:") {
+        let parsed = score
+            .split_once(':')
+            .and_then(|(bot, points)| Some((bot.to_string(), points.parse().ok()?)));
+        if let Some((bot, points)) = parsed {
+            sender
+                .send(BrokerAction::Score {
+                    id,
+                    time: Local::now(),
+                    bot,
+                    points,
+                })
+                .await
+                .ok();
+        } else {
+            let message = format!("invalid score '{}'", score);
+            sender
+                .send(BrokerAction::ClientError { id, message })
+                .await
+                .ok();
+        }
+    } else if line == "SPECTATOR" {
+        let (result_sender, receiver) = oneshot::channel();
+        sender
+            .send(BrokerAction::SpectatorClaim {
+                id,
+                sender: result_sender,
+            })
+            .await
+            .ok();
+        if receiver.await.is_err() {
+            return false;
+        }
+    } else if let Some(text) = line.strip_prefix(ANNOUNCE_PREFIX) {
+        sender
+            .send(BrokerAction::Announce {
+                id,
+                time: Local::now(),
+                text: text.to_string(),
+                to_bots: false,
+            })
+            .await
+            .ok();
+    } else if let Some(text) = line.strip_prefix("ANNOUNCE_BOTS:") {
+        sender
+            .send(BrokerAction::Announce {
+                id,
+                time: Local::now(),
+                text: text.to_string(),
+                to_bots: true,
+            })
+            .await
+            .ok();
+    } else if let Some(subscription) = line.strip_prefix("SUBSCRIBE:") {
+        let subscription = subscription.to_string();
+        sender
+            .send(BrokerAction::Subscribe { id, subscription })
+            .await
+            .ok();
+    } else if line == "LIST" {
+        sender.send(BrokerAction::List { id }).await.ok();
+    } else if let Some(name) = line.strip_prefix("STATUS:") {
+        let name = name.to_string();
+        sender.send(BrokerAction::Status { id, name }).await.ok();
+    } else if line == "WHOAMI" {
+        sender.send(BrokerAction::Whoami { id }).await.ok();
+    } else if line == "ARENAS" {
+        sender.send(BrokerAction::Arenas { id }).await.ok();
+    } else if line == "BOARD" {
+        sender.send(BrokerAction::Board { id }).await.ok();
+    } else if line == "PARAMS" {
+        sender.send(BrokerAction::ParamList { id }).await.ok();
+    } else if let Some(name) = line.strip_prefix("GET:") {
+        let name = name.to_string();
+        sender.send(BrokerAction::ParamGet { id, name }).await.ok();
+    } else if let Some(assignment) = line.strip_prefix("SET:") {
+        if let Some((name, value)) = assignment.split_once('=') {
+            sender
+                .send(BrokerAction::ParamSet {
+                    id,
+                    time: Local::now(),
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .await
+                .ok();
+        } else {
+            let message = format!("invalid parameter assignment '{}'", assignment);
+            sender
+                .send(BrokerAction::ClientError { id, message })
+                .await
+                .ok();
+        }
+    } else if let Some(queued) = line.strip_prefix("QUEUE:") {
+        let parsed = queued.split_once(':').and_then(|(seconds, command)| {
+            let seconds: u64 = seconds.parse().ok()?;
+            let command = match command.as_bytes() {
+                [byte] => PrivateCommand::decode(*byte)?,
+                _ => return None,
+            };
+            Some((Duration::from_secs(seconds), command))
+        });
+        match parsed {
+            Some((queue_for, command)) if queue_for <= MAX_QUEUE_TIME => {
+                sender
+                    .send(BrokerAction::PrivateCommand {
+                        id,
+                        time: Local::now(),
+                        command,
+                        queue_for: Some(queue_for),
+                    })
+                    .await
+                    .ok();
+            }
+            Some(_) => {
+                let message = format!(
+                    "commands cannot be queued for more than {}s",
+                    MAX_QUEUE_TIME.as_secs()
+                );
+                sender
+                    .send(BrokerAction::ClientError { id, message })
+                    .await
+                    .ok();
+            }
+            None => {
+                let message = format!("invalid queued command '{}'", queued);
+                sender
+                    .send(BrokerAction::ClientError { id, message })
+                    .await
+                    .ok();
+            }
+        }
+    } else if let Some(addressed) = line.strip_prefix("TO:") {
+        let parsed = addressed.split_once(':').and_then(|(bot, command)| {
+            let command = match command.as_bytes() {
+                [byte] => PrivateCommand::decode(*byte)?,
+                _ => return None,
+            };
+            let bot = if bot == "*" {
+                None
+            } else {
+                Some(bot.to_string())
+            };
+            Some((bot, command))
+        });
+        if let Some((bot, command)) = parsed {
+            sender
+                .send(BrokerAction::TeamCommand {
+                    id,
+                    time: Local::now(),
+                    bot,
+                    command,
+                })
+                .await
+                .ok();
+        } else {
+            let message = format!("invalid team command '{}'", addressed);
+            sender
+                .send(BrokerAction::ClientError { id, message })
+                .await
+                .ok();
+        }
+    } else if line.len() == 1 {
+        let byte = line.as_bytes()[0];
+        match BotCommand::decode(byte) {
+            Some(BotCommand::Referee(command)) => {
+                sender
+                    .send(BrokerAction::RefereeCommand {
+                        id,
+                        time: Local::now(),
+                        command,
+                    })
+                    .await
+                    .ok();
+            }
+            Some(BotCommand::Private(command)) => {
+                sender
+                    .send(BrokerAction::PrivateCommand {
+                        id,
+                        time: Local::now(),
+                        command,
+                        queue_for: None,
+                    })
+                    .await
+                    .ok();
+            }
+            None => {
+                let message = format!("invalid command character in '{}'", line);
+                sender
+                    .send(BrokerAction::ClientError { id, message })
+                    .await
+                    .ok();
+            }
+        }
+    } else {
+        let message = format!("invalid command '{}'", line);
+        sender
+            .send(BrokerAction::ClientError { id, message })
+            .await
+            .ok();
+    }
+    true
+}
+
+/// Passes the lines of a client to the broker, within its rate limit, until it disconnects
+async fn broker_cmd_reader(
+    id: Ulid,
+    mut lines: Lines<BufReader<OwnedReadHalf>>,
+    sender: &BrokerActionSender,
+    limit: RateLimit,
+) {
+    let mut limiter = Limiter::new(limit);
+    loop {
+        let line = if limiter.is_dropping() {
+            match timeout(MARKER_INTERVAL, lines.next_line()).await {
+                Ok(line) => line,
+                Err(_) => {
+                    if let Some(marker) = limiter.marker(true) {
+                        send_dropped(id, marker, sender).await;
+                    }
+                    continue;
+                }
+            }
+        } else {
+            lines.next_line().await
+        };
+        let line = match line {
+            Ok(Some(line)) => line,
+            _ => break,
+        };
+        match limiter.admit(None, Some(&line)) {
+            Admission::Accept => {
+                if let Some(marker) = limiter.marker(true) {
+                    send_dropped(id, marker, sender).await;
+                }
+            }
+            Admission::Drop => {
+                if let Some(marker) = limiter.marker(false) {
+                    send_dropped(id, marker, sender).await;
+                }
+                continue;
+            }
+            Admission::Disconnect => {
+                send_dropped(id, limiter.disconnect_message(), sender).await;
+                break;
+            }
+        }
+        if !broker_cmd_line(id, line, sender).await {
+            break;
+        }
+    }
+}
+
+async fn broker_cmd_listener(listener: TcpListener, sender: BrokerActionSender, limit: RateLimit) {
+    let broker_sender = sender;
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                let (reader, writer) = stream.into_split();
+                let id = Ulid::new();
+                let (stop_reader, reader_stopped) = oneshot::channel();
                 let (sender, receiver) = oneshot::channel();
                 if broker_sender
-                    .send(BrokerAction::BotJoin {
+                    .send(BrokerAction::Join {
                         id,
                         address,
                         writer,
+                        stop_reader,
                         sender,
                     })
                     .await
@@ -719,358 +4546,1465 @@ async fn broker_bot_listener(listener: TcpListener, sender: BrokerActionSender)
                 {
                     receiver.await.ok();
 
-                    let bot_broker_sender = broker_sender.clone();
-                    let buf_reader = BufReader::new(reader);
-                    let mut lines = buf_reader.lines();
+                    let cmd_broker_sender = broker_sender.clone();
+                    let lines = BufReader::new(reader).lines();
                     spawn(async move {
-                        loop {
-                            match lines.next_line().await {
-                                Ok(line) => {
-                                    if let Some(mut line) = line {
-                                        if let Some(l) = line.strip_suffix("\n") {
-                                            line = l.to_string();
-                                        }
-                                        if let Some(name) = line.strip_prefix("NAME:") {
-                                            let name = name.to_string();
-                                            let (sender, receiver) = oneshot::channel();
-                                            bot_broker_sender
-                                                .send(BrokerAction::BotNameClaim {
-                                                    id,
-                                                    name,
-                                                    sender,
-                                                })
-                                                .await
-                                                .ok();
-                                            if let Ok(result) = receiver.await {
-                                                if let Err(err) = result {
-                                                    println!(
-                                                        "{}: bot name claim ignored: {}",
-                                                        Local::now(),
-                                                        err
-                                                    );
-                                                }
-                                            } else {
-                                                break;
-                                            }
-                                        } else {
-                                            bot_broker_sender
-                                                .send(BrokerAction::Log {
-                                                    id,
-                                                    time: Local::now(),
-                                                    message: line,
-                                                })
-                                                .await
-                                                .ok();
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                Err(_) => {
-                                    break;
-                                }
-                            }
+                        select! {
+                            _ = broker_cmd_reader(id, lines, &cmd_broker_sender, limit) => {}
+                            _ = reader_stopped => {}
                         }
+                        cmd_broker_sender
+                            .send(BrokerAction::Leave { id })
+                            .await
+                            .ok();
                     });
                 }
             }
-            Err(err) => {
-                println!("{}: error listening on log port: {}", Local::now(), err);
+            Err(err) => {
+                println!("{}: error listening on cmd port: {}", Local::now(), err);
+            }
+        }
+    }
+}
+
+async fn broker_console(sender: BrokerActionSender) {
+    println!("broker console: type 'help' for the operator commands");
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if sender.send(BrokerAction::Console { line }).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Resolves when the broker is asked to terminate with Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
+                }
+            }
+            Err(_) => {
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Joins an address and a port, bracketing IPv6 addresses
+fn socket_address(address: &str, port: u16) -> String {
+    if address.contains(':') && !address.starts_with('[') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+async fn resolve_address(address: &str, port: u16) -> Result<SocketAddr, Box<dyn Error>> {
+    let addr = socket_address(address, port);
+    let resolved = tokio::net::lookup_host(&addr).await?.next();
+    Ok(resolved.ok_or_else(|| format!("cannot resolve {}", addr))?)
+}
+
+/// Binds a listener, keeping IPv6 listeners off IPv4 so that both can share a port
+fn listen(addr: SocketAddr) -> Result<TcpListener, Box<dyn Error>> {
+    let bind = || {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    };
+    Ok(bind().map_err(|err: std::io::Error| format!("cannot listen on {}: {}", addr, err))?)
+}
+
+async fn broker(
+    bot_port: u16,
+    client_port: u16,
+    args: BrokerArguments,
+) -> Result<(), Box<dyn Error>> {
+    let (bot_limit, client_limit) = (args.bot_limit(), args.client_limit());
+    let mut bot_addrs = args.bot_listen;
+    if bot_addrs.is_empty() {
+        bot_addrs.push(resolve_address(&args.address, bot_port).await?);
+    }
+    let mut cmd_addrs = args.client_listen;
+    if cmd_addrs.is_empty() {
+        cmd_addrs.push(resolve_address(&args.address, client_port).await?);
+    }
+    let (broker_sender, mut broker_receiver) = mpsc::channel(32);
+
+    for addr in bot_addrs {
+        spawn(broker_bot_listener(
+            listen(addr)?,
+            broker_sender.clone(),
+            bot_limit,
+        ));
+        println!("{}: listening for bots on {}", Local::now(), addr);
+    }
+    for addr in cmd_addrs {
+        spawn(broker_cmd_listener(
+            listen(addr)?,
+            broker_sender.clone(),
+            client_limit,
+        ));
+        println!("{}: listening for clients on {}", Local::now(), addr);
+    }
+    spawn(broker_console(broker_sender.clone()));
+
+    let mut broker = Broker::new();
+    if let Some(state) = args.state {
+        broker.load_state(state)?;
+    }
+    if let Some(record) = args.record {
+        broker.start_recording(record)?;
+    }
+    if let Some(audit) = args.audit {
+        broker.open_audit_log(audit)?;
+    }
+    let hooks = args.hook.into_iter().chain(args.hook_pipe).collect();
+    broker.set_hooks(Hooks::new(
+        hooks,
+        Duration::try_from_secs_f64(args.hook_timeout)?,
+    ));
+    let mut ping_interval = interval(PING_INTERVAL);
+    let mut match_timer_interval = interval(MATCH_TIMER_INTERVAL);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let action = select! {
+            action = broker_receiver.recv() => match action {
+                Some(action) => action,
+                None => break,
+            },
+            _ = ping_interval.tick() => {
+                broker.measure_latency().await;
+                broker.expire_queued_commands().await;
+                broker.expire_sessions();
+                broker.flush_recording();
+                continue;
+            }
+            _ = match_timer_interval.tick() => {
+                broker.check_match_timers().await;
+                continue;
+            }
+            _ = &mut shutdown => break,
+        };
+        broker.handle(action).await;
+        if broker.is_shutting_down {
+            break;
+        }
+    }
+    broker.shutdown(&mut broker_receiver).await;
+
+    Ok(())
+}
+
+fn print_client_line(line: &str) {
+    if line == INFO_END {
+        return;
+    }
+    if let Some(info) = line.strip_prefix(INFO_PREFIX) {
+        println!("{}", info);
+        return;
+    }
+    if let Some(error) = line.strip_prefix(ERROR_PREFIX) {
+        println!("\x1b[1;31merror: {}\x1b[0m", error);
+        return;
+    }
+    if let Some(announcement) = line.strip_prefix(ANNOUNCE_PREFIX) {
+        println!("\x1b[1;7;33m*** {} ***\x1b[0m", announcement);
+        return;
+    }
+    if let Some((level, log)) = line
+        .strip_prefix(LOG_PREFIX)
+        .and_then(|log| log.split_once(':'))
+    {
+        if let Some(level) = LogLevel::decode(level) {
+            match level.color() {
+                "" => println!("{}", log),
+                color => println!("{}{}\x1b[0m", color, log),
+            }
+            return;
+        }
+    }
+    if let Some(telemetry) = TelemetryLine::decode(line) {
+        let samples: Vec<String> = telemetry
+            .samples
+            .iter()
+            .map(|sample| match &sample.unit {
+                Some(unit) => format!("{} {} {}", sample.signal, sample.value, unit),
+                None => format!("{} {}", sample.signal, sample.value),
+            })
+            .collect();
+        println!(
+            "\x1b[2m{} at {}ms: {}\x1b[0m",
+            telemetry.bot,
+            telemetry.bot_time_ms,
+            samples.join(", ")
+        );
+        return;
+    }
+    if let Some((bot, param)) = line
+        .strip_prefix("PARAM:")
+        .and_then(|param| param.split_once(':'))
+    {
+        if let Some(param) = BotParam::decode(param) {
+            println!("{}: {}", bot, param);
+            return;
+        }
+    }
+    println!("{}", line);
+}
+
+fn params_request(line: &str) -> String {
+    let line = line.trim();
+    if line.is_empty() {
+        "PARAMS".to_string()
+    } else if let Some((name, value)) = line.split_once('=') {
+        format!("SET:{}={}", name.trim(), value.trim())
+    } else {
+        format!("GET:{}", line)
+    }
+}
+
+async fn cmd_query(
+    client_port: u16,
+    address: String,
+    name: Option<String>,
+    team: Option<String>,
+    query: &str,
+) -> Result<(), Box<dyn Error>> {
+    let addr = socket_address(&address, client_port);
+    let stream = TcpStream::connect(&addr).await?;
+    let (reply_stream, mut cmd_stream) = stream.into_split();
+
+    if let Some(name) = name {
+        let line = format!("NAME:{}\n", name);
+        cmd_stream.write_all(line.as_bytes()).await?;
+    }
+    if let Some(team) = team {
+        let line = format!("TEAM:{}\n", team);
+        cmd_stream.write_all(line.as_bytes()).await?;
+    }
+    cmd_stream
+        .write_all(format!("{}\n", query).as_bytes())
+        .await?;
+
+    let mut lines = BufReader::new(reply_stream).lines();
+    let replies = async {
+        while let Some(line) = lines.next_line().await? {
+            if line == INFO_END {
+                break;
+            }
+            if line.starts_with(INFO_PREFIX) || line.starts_with(ERROR_PREFIX) {
+                print_client_line(&line);
+            }
+        }
+        Ok::<(), std::io::Error>(())
+    };
+    tokio::time::timeout(QUERY_TIMEOUT, replies)
+        .await
+        .map_err(|_| "no reply from broker")??;
+
+    Ok(())
+}
+
+/// Referee commands, announcements and match updates typed while disconnected are not replayed on
+/// reconnect, because by then they may no longer reflect what the referee wants
+fn is_referee_line(line: &str) -> bool {
+    let is_referee_command =
+        line.len() == 1 && RefereeCommand::decode(line.as_bytes()[0]).is_some();
+    is_referee_command
+        || line.starts_with(ANNOUNCE_PREFIX)
+        || line.starts_with("ANNOUNCE_BOTS:")
+        || line.starts_with("MATCH:")
+        || line.starts_with("SCORE:")
+}
+
+/// What the connection to the broker reports to the client front end
+pub enum ClientEvent {
+    /// A line received from the broker
+    Line(String),
+    /// A note about the connection itself
+    Status(String),
+}
+
+pub type ClientEventSender = mpsc::UnboundedSender<ClientEvent>;
+
+fn client_status(events: &ClientEventSender, status: String) {
+    events.send(ClientEvent::Status(status)).ok();
+}
+
+/// Waits before reconnecting, buffering the lines sent in the meantime
+///
+/// Returns false if the input terminated while waiting.
+async fn client_wait(
+    delay: Duration,
+    input: &mut mpsc::Receiver<String>,
+    buffered: &mut Vec<String>,
+    events: &ClientEventSender,
+) -> bool {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        select! {
+            _ = &mut sleep => return true,
+            line = input.recv() => match line {
+                Some(line) => {
+                    client_status(events, format!("[disconnected] buffered '{}'", &line));
+                    buffered.push(line);
+                }
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Keeps a client connected to the broker until its input terminates
+///
+/// The claims are sent again on every connection; lines from the input are sent to
+/// the broker, or buffered while disconnected, and lines from the broker are passed
+/// on as events, pings excluded.
+async fn client_connection(
+    addr: String,
+    claims: Vec<String>,
+    mut input: mpsc::Receiver<String>,
+    events: ClientEventSender,
+) {
+    let mut buffered = Vec::new();
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        let stream = match TcpStream::connect(&addr).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                client_status(
+                    &events,
+                    format!(
+                        "{}: [disconnected] cannot connect to {}: {}, retrying in {}s",
+                        Local::now(),
+                        &addr,
+                        err,
+                        delay.as_secs_f32()
+                    ),
+                );
+                if !client_wait(delay, &mut input, &mut buffered, &events).await {
+                    break;
+                }
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                continue;
+            }
+        };
+        let (log_stream, mut cmd_stream) = stream.into_split();
+
+        let (closed_sender, mut closed_receiver) = oneshot::channel::<()>();
+        let log_events = events.clone();
+        let log_task = spawn(async move {
+            let log_reader = BufReader::new(log_stream);
+            let mut lines = log_reader.lines();
+            loop {
+                match lines.next_line().await {
+                    Ok(line) => {
+                        if let Some(line) = line {
+                            if line != PING_LINE {
+                                log_events.send(ClientEvent::Line(line)).ok();
+                            }
+                        } else {
+                            client_status(
+                                &log_events,
+                                format!("{}: logs terminated", Local::now()),
+                            );
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        client_status(
+                            &log_events,
+                            format!("{}: error reading logs: {}", Local::now(), err),
+                        );
+                        break;
+                    }
+                }
+            }
+            closed_sender.send(()).ok();
+        });
+
+        let mut lines = claims.clone();
+        for line in buffered.drain(..) {
+            if is_referee_line(&line) {
+                client_status(
+                    &events,
+                    format!(
+                        "not replaying '{}' typed while disconnected, type it again if still needed",
+                        line
+                    ),
+                );
+            } else {
+                lines.push(line);
+            }
+        }
+        let mut is_connected = true;
+        for line in lines.iter() {
+            if cmd_stream
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .is_err()
+            {
+                is_connected = false;
+                break;
+            }
+        }
+
+        while is_connected {
+            select! {
+                line = input.recv() => match line {
+                    Some(line) => {
+                        if cmd_stream
+                            .write_all(format!("{}\n", line).as_bytes())
+                            .await
+                            .is_err()
+                        {
+                            client_status(&events, format!("[disconnected] buffered '{}'", &line));
+                            buffered.push(line);
+                            is_connected = false;
+                        }
+                    }
+                    None => {
+                        log_task.abort();
+                        return;
+                    }
+                },
+                _ = &mut closed_receiver => {
+                    is_connected = false;
+                }
+            }
+        }
+
+        delay = RECONNECT_MIN_DELAY;
+        client_status(
+            &events,
+            format!(
+                "{}: [disconnected] lost connection to {}, reconnecting",
+                Local::now(),
+                &addr
+            ),
+        );
+        if !client_wait(delay, &mut input, &mut buffered, &events).await {
+            break;
+        }
+    }
+
+    if !buffered.is_empty() {
+        client_status(
+            &events,
+            format!("discarding {} buffered lines", buffered.len()),
+        );
+    }
+}
+
+/// Who a client says it is, replayed to the broker on every connection
+#[derive(Default)]
+pub struct ClientClaims {
+    name: Option<String>,
+    team: Option<String>,
+    arena: Option<String>,
+    is_referee: bool,
+    is_spectator: bool,
+    subscriptions: Vec<String>,
+}
+
+impl ClientClaims {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(name) = &self.name {
+            lines.push(format!("NAME:{}", name));
+        }
+        if let Some(team) = &self.team {
+            lines.push(format!("TEAM:{}", team));
+        }
+        for subscription in self.subscriptions.iter() {
+            lines.push(format!("SUBSCRIBE:{}", subscription));
+        }
+        if self.is_referee {
+            lines.push("REFEREE".to_string());
+        }
+        if let Some(arena) = &self.arena {
+            lines.push(format!("ARENA:{}", arena));
+        }
+        if self.is_spectator {
+            lines.push("SPECTATOR".to_string());
+        }
+        lines
+    }
+}
+
+async fn cmd_client(
+    client_port: u16,
+    address: String,
+    client: ClientClaims,
+    mode: Option<CmdMode>,
+) -> Result<(), Box<dyn Error>> {
+    let addr = socket_address(&address, client_port);
+
+    let mut claims = client.lines();
+    if mode == Some(CmdMode::Params) {
+        claims.push("PARAMS".to_string());
+    }
+
+    if client.is_referee {
+        println!(
+            "referee: '{}<text>' announces to clients, 'ANNOUNCE_BOTS:<text>' also to bots",
+            ANNOUNCE_PREFIX
+        );
+    }
+    if client.arena.is_some() {
+        println!(
+            "arena: 'MATCH:<bot>,<bot>[:<seconds>]' assigns the next match, \
+             'SCORE:<bot>:<points>' awards points"
+        );
+    }
+    if client.team.is_some() {
+        println!(
+            "team: 'TO:<bot>:<command>' sends to one bot of the team, 'TO:*:<command>' to all"
+        );
+    }
+    if mode == Some(CmdMode::Params) {
+        println!("parameters: '<name>' reads, '<name>=<value>' writes, an empty line lists all");
+    }
+
+    let (stdin_sender, stdin_receiver) = mpsc::channel(32);
+    spawn(async move {
+        let stdin_reader = BufReader::new(tokio::io::stdin());
+        let mut stdin_lines = stdin_reader.lines();
+        loop {
+            match stdin_lines.next_line().await {
+                Ok(Some(mut line)) => {
+                    if mode == Some(CmdMode::Params) {
+                        line = params_request(&line);
+                    }
+                    if stdin_sender.send(line).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    println!("stdin terminated, exiting");
+                    break;
+                }
+                Err(err) => {
+                    println!("error reading from stdin, exiting: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    let printer = spawn(async move {
+        while let Some(event) = event_receiver.recv().await {
+            match event {
+                ClientEvent::Line(line) => print_client_line(&line),
+                ClientEvent::Status(status) => println!("{}", status),
+            }
+        }
+    });
+    client_connection(addr, claims, stdin_receiver, event_sender).await;
+    printer.await?;
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    let global_args = Arguments::parse();
+
+    match global_args.action {
+        SubCommand::Broker(args) => {
+            broker(global_args.bot_port, global_args.client_port, args).await
+        }
+        SubCommand::Referee(args) => {
+            let client = ClientClaims {
+                is_referee: true,
+                subscriptions: args
+                    .arena
+                    .iter()
+                    .map(|arena| format!("arenas={}", arena))
+                    .collect(),
+                arena: args.arena,
+                ..Default::default()
+            };
+            if args.dashboard {
+                let addr = socket_address(&args.address, global_args.client_port);
+                dashboard::run(addr, client.lines()).await
+            } else {
+                cmd_client(global_args.client_port, args.address, client, None).await
+            }
+        }
+        SubCommand::Spectate(args) => {
+            let mut subscriptions = Vec::new();
+            if !args.arena.is_empty() {
+                subscriptions.push(format!("arenas={}", args.arena.join(",")));
+            }
+            let client = ClientClaims {
+                is_spectator: true,
+                subscriptions,
+                ..Default::default()
+            };
+            cmd_client(global_args.client_port, args.address, client, None).await
+        }
+        SubCommand::Audit(args) => audit::summarise(&args.file, &args.action),
+        SubCommand::SerialBridge(args) => {
+            let options = bridge::BridgeOptions {
+                broker: socket_address(&args.address, global_args.bot_port),
+                port: args.port,
+                baud_rate: args.baud_rate,
+                name: args.name,
+                team: args.team,
+            };
+            bridge::run(options).await
+        }
+        SubCommand::PanicDecode(args) => {
+            let report = panic::decode(&args.file, args.elf.as_deref())?;
+            println!("{}", report);
+            match (args.record, args.bot) {
+                (Some(record), Some(bot)) => {
+                    panic::attach(record.clone(), &bot, &args.file, &report)?;
+                    println!(
+                        "report attached to the log of {} in {}",
+                        bot,
+                        record.display()
+                    );
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+        SubCommand::Results(args) => {
+            let bouts = match (args.state, args.recording) {
+                (Some(state), _) => results::state_bouts(&TournamentState::load(&state)?),
+                (None, Some(recording)) => {
+                    let (events, invalid_lines) = read_recording(&recording)?;
+                    if invalid_lines > 0 {
+                        println!("{} invalid lines skipped", invalid_lines);
+                    }
+                    results::recording_bouts(&events)
+                }
+                (None, None) => Vec::new(),
+            };
+            let options = results::ResultsOptions {
+                arenas: args.arena,
+                output: args.output,
+                title: args.title,
+            };
+            results::export(bouts, &options)
+        }
+        SubCommand::Telemetry(args) => match args.action {
+            TelemetryAction::Export(args) => {
+                let options = args.export_options()?;
+                let (events, invalid_lines) = read_recording(&args.file)?;
+                if invalid_lines > 0 {
+                    println!("{} invalid lines skipped", invalid_lines);
+                }
+                telemetry::export(&events, &options)
+            }
+        },
+        SubCommand::Cmd(args) => match args.mode.clone() {
+            Some(CmdMode::List) => {
+                cmd_query(global_args.client_port, args.address, None, None, "LIST").await
             }
+            Some(CmdMode::Status { name }) => {
+                let name = name.or(args.name).ok_or("no bot name given")?;
+                let query = format!("STATUS:{}", name);
+                cmd_query(global_args.client_port, args.address, None, None, &query).await
+            }
+            Some(CmdMode::Whoami) => {
+                cmd_query(
+                    global_args.client_port,
+                    args.address,
+                    args.name,
+                    args.team,
+                    "WHOAMI",
+                )
+                .await
+            }
+            Some(CmdMode::Arenas) => {
+                cmd_query(global_args.client_port, args.address, None, None, "ARENAS").await
+            }
+            mode => {
+                let client = ClientClaims {
+                    subscriptions: args.subscriptions(),
+                    name: args.name,
+                    team: args.team,
+                    is_referee: args.referee,
+                    ..Default::default()
+                };
+                cmd_client(global_args.client_port, args.address, client, mode).await
+            }
+        },
+    }?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bot_param_round_trips() {
+        for text in [
+            "speed:int:3",
+            "gain:float:0.5:0:2",
+            "led:bool:1",
+            "k.p:int:-4::10",
+        ] {
+            assert_eq!(BotParam::decode(text).unwrap().encode(), text);
         }
     }
-}
 
-async fn broker_cmd_listener(listener: TcpListener, sender: BrokerActionSender) {
-    let broker_sender = sender;
-    loop {
-        match listener.accept().await {
-            Ok((stream, address)) => {
-                let (reader, writer) = stream.into_split();
-                let id = Ulid::new();
-                let (sender, receiver) = oneshot::channel();
-                if broker_sender
-                    .send(BrokerAction::Join {
-                        id,
-                        address,
-                        writer,
-                        sender,
-                    })
-                    .await
-                    .is_ok()
-                {
-                    receiver.await.ok();
+    #[test]
+    fn bot_param_rejects_malformed_lines() {
+        for text in [
+            "",
+            "speed",
+            "speed:long:3",
+            "speed:int:fast",
+            "speed:int:3:0:9:1",
+            "bad name:int:3",
+            "led:bool:maybe",
+        ] {
+            assert!(BotParam::decode(text).is_none(), "{}", text);
+        }
+    }
 
-                    let cmd_broker_sender = broker_sender.clone();
-                    let buf_reader = BufReader::new(reader);
-                    let mut lines = buf_reader.lines();
-                    spawn(async move {
-                        loop {
-                            match lines.next_line().await {
-                                Ok(line) => {
-                                    if let Some(mut line) = line {
-                                        if let Some(l) = line.strip_suffix("\n") {
-                                            line = l.to_string();
-                                        }
-                                        if let Some(name) = line.strip_prefix("NAME:") {
-                                            let name = name.to_string();
-                                            let (sender, receiver) = oneshot::channel();
-                                            cmd_broker_sender
-                                                .send(BrokerAction::NameClaim { id, name, sender })
-                                                .await
-                                                .ok();
-                                            if let Ok(result) = receiver.await {
-                                                if let Err(err) = result {
-                                                    println!(
-                                                        "{}: client name claim ignored: {}",
-                                                        Local::now(),
-                                                        err
-                                                    );
-                                                }
-                                            } else {
-                                                break;
-                                            }
-                                        } else if line == "REFEREE" {
-                                            let (sender, receiver) = oneshot::channel();
-                                            cmd_broker_sender
-                                                .send(BrokerAction::RefereeClaim { id, sender })
-                                                .await
-                                                .ok();
-                                            if let Ok(result) = receiver.await {
-                                                if let Err(err) = result {
-                                                    println!(
-                                                        "{}: client referee claim ignored: {}",
-                                                        Local::now(),
-                                                        err
-                                                    );
-                                                }
-                                            } else {
-                                                break;
-                                            }
-                                        } else {
-                                            if line.len() == 1 {
-                                                let byte = line.chars().next().unwrap() as u8;
-                                                let cmd = BotCommand::decode(byte);
-                                                if let Some(cmd) = cmd {
-                                                    match cmd {
-                                                        BotCommand::Referee(command) => {
-                                                            cmd_broker_sender
-                                                                .send(
-                                                                    BrokerAction::RefereeCommand {
-                                                                        id,
-                                                                        time: Local::now(),
-                                                                        command,
-                                                                    },
-                                                                )
-                                                                .await
-                                                                .ok();
-                                                        }
-                                                        BotCommand::Private(command) => {
-                                                            cmd_broker_sender
-                                                                .send(
-                                                                    BrokerAction::PrivateCommand {
-                                                                        id,
-                                                                        time: Local::now(),
-                                                                        command,
-                                                                    },
-                                                                )
-                                                                .await
-                                                                .ok();
-                                                        }
-                                                    }
-                                                } else {
-                                                    println!(
-                                                        "{}: invalid command character in '{}'",
-                                                        Local::now(),
-                                                        &line
-                                                    );
-                                                }
-                                            } else {
-                                                println!(
-                                                    "{}: invalid command '{}'",
-                                                    Local::now(),
-                                                    &line
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                Err(_) => {
-                                    break;
-                                }
-                            }
-                        }
-                    });
+    #[test]
+    fn bot_param_checks_type_and_range() {
+        let param = BotParam::decode("speed:int:3:0:10").unwrap();
+        assert_eq!(param.check("10"), Ok(ParamValue::Int(10)));
+        assert!(param.check("11").is_err());
+        assert!(param.check("-1").is_err());
+        assert!(param.check("2.5").is_err());
+
+        let param = BotParam::decode("gain:float:0.5:0:2").unwrap();
+        assert_eq!(param.check("1.5"), Ok(ParamValue::Float(1.5)));
+        for text in ["NaN", "nan", "inf", "-inf"] {
+            assert!(param.check(text).is_err(), "{}", text);
+        }
+        assert!(BotParam::decode("gain:float:NaN").is_none());
+
+        let param = BotParam::decode("led:bool:0").unwrap();
+        assert_eq!(param.check("on"), Ok(ParamValue::Bool(true)));
+        assert_eq!(param.check("false"), Ok(ParamValue::Bool(false)));
+    }
+
+    /// The far end of a broker connection, reading what the broker writes to it
+    struct Peer {
+        stream: TcpStream,
+    }
+
+    impl Peer {
+        /// What the broker wrote since the last call, once it stops writing for a moment
+        async fn received(&mut self) -> String {
+            let mut data = Vec::new();
+            let mut buffer = [0; 1024];
+            while let Ok(Ok(length)) =
+                timeout(Duration::from_millis(50), self.stream.read(&mut buffer)).await
+            {
+                if length == 0 {
+                    break;
                 }
+                data.extend_from_slice(&buffer[..length]);
             }
-            Err(err) => {
-                println!("{}: error listening on cmd port: {}", Local::now(), err);
-            }
+            String::from_utf8_lossy(&data).to_string()
         }
-    }
-}
 
-async fn broker(
-    bot_port: u16,
-    client_port: u16,
-    args: BrokerArguments,
-) -> Result<(), Box<dyn Error>> {
-    let bot_addr = format!("{}:{}", &args.address, bot_port);
-    let cmd_addr = format!("{}:{}", &args.address, client_port);
-    let bot_listener = TcpListener::bind(&bot_addr).await?;
-    let cmd_listener = TcpListener::bind(&cmd_addr).await?;
-    let (broker_sender, mut broker_receiver) = mpsc::channel(32);
+        async fn lines(&mut self) -> Vec<String> {
+            self.received().await.lines().map(str::to_string).collect()
+        }
 
-    spawn(broker_bot_listener(bot_listener, broker_sender.clone()));
-    spawn(broker_cmd_listener(cmd_listener, broker_sender.clone()));
+        /// Whether the broker closed the connection, after what it wrote before
+        async fn is_closed(&mut self) -> bool {
+            self.received().await;
+            let mut buffer = [0; 1];
+            matches!(
+                timeout(Duration::from_millis(50), self.stream.read(&mut buffer)).await,
+                Ok(Ok(0) | Err(_))
+            )
+        }
 
-    let mut broker = Broker::new();
+        /// The command bytes a bot received, without the pings around them
+        async fn commands(&mut self) -> String {
+            self.received().await.replace(['\0', '\n'], "")
+        }
+    }
 
-    while let Some(action) = broker_receiver.recv().await {
-        match action {
-            BrokerAction::BotJoin {
+    async fn connection() -> (OwnedWriteHalf, SocketAddr, Peer) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, address) = listener.accept().await.unwrap();
+        let (_, writer) = accepted.into_split();
+        (writer, address, Peer { stream })
+    }
+
+    async fn join_client(broker: &mut Broker) -> (Ulid, Peer) {
+        let (writer, address, peer) = connection().await;
+        let id = Ulid::new();
+        let (stop_reader, _) = oneshot::channel();
+        let (sender, receiver) = oneshot::channel();
+        broker
+            .handle(BrokerAction::Join {
                 id,
                 address,
                 writer,
+                stop_reader,
                 sender,
-            } => {
-                broker.bot_join(id, address, writer, sender).await;
-            }
-            BrokerAction::BotNameClaim { id, name, sender } => {
-                broker.bot_name_claim(id, name, sender).await;
+            })
+            .await;
+        receiver.await.unwrap().unwrap();
+        (id, peer)
+    }
+
+    /// Passes a line from a client through its parser to the broker
+    async fn client_line(broker: &mut Broker, id: Ulid, line: &str) {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let line = line.to_string();
+        let parse = async move { broker_cmd_line(id, line, &sender).await };
+        let handle = async {
+            while let Some(action) = receiver.recv().await {
+                broker.handle(action).await;
             }
-            BrokerAction::Join {
+        };
+        tokio::join!(parse, handle);
+    }
+
+    #[tokio::test]
+    async fn errors_only_reach_the_client_that_caused_them() {
+        let mut broker = Broker::new();
+        let (id, mut peer) = join_client(&mut broker).await;
+        let (_, mut other) = join_client(&mut broker).await;
+
+        for line in ["!", "a", "NAME:bad/name", "WHAT"] {
+            client_line(&mut broker, id, line).await;
+        }
+        let lines = peer.lines().await;
+        let errors: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix(ERROR_PREFIX))
+            .map(|error| error.rsplit_once(':').unwrap().1)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "invalid command character in '!'",
+                "discarding private command 'a' from unnamed client",
+                "invalid name 'bad/name'",
+                "invalid command 'WHAT'",
+            ]
+        );
+        assert_eq!(other.received().await, "");
+    }
+
+    async fn join_bot(broker: &mut Broker) -> (Ulid, Peer) {
+        let (writer, address, peer) = connection().await;
+        let id = Ulid::new();
+        let (stop_reader, _) = oneshot::channel();
+        let (sender, receiver) = oneshot::channel();
+        broker
+            .handle(BrokerAction::BotJoin {
                 id,
                 address,
                 writer,
+                stop_reader,
                 sender,
-            } => {
-                broker.join(id, address, writer, sender).await;
-            }
-            BrokerAction::NameClaim { id, name, sender } => {
-                broker.name_claim(id, name, sender).await;
-            }
-            BrokerAction::RefereeClaim { id, sender } => {
-                broker.referee_claim(id, sender).await;
-            }
-            BrokerAction::Log { id, time, message } => {
-                broker.log(id, time, message).await;
-            }
-            BrokerAction::RefereeCommand { id, time, command } => {
-                broker.referee_command(id, time, command).await;
-            }
-            BrokerAction::PrivateCommand { id, time, command } => {
-                broker.private_command(id, time, command).await;
-            }
-            BrokerAction::BotLeave { id } => {
-                broker.bot_leave(id).await;
-            }
-            BrokerAction::Leave { id } => {
-                broker.leave(id).await;
+            })
+            .await;
+        receiver.await.unwrap().unwrap();
+        (id, peer)
+    }
+
+    /// Passes a line from a bot through its parser to the broker
+    async fn bot_line(broker: &mut Broker, id: Ulid, line: &str) {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let line = line.to_string();
+        let parse = async move { broker_bot_line(id, line, &sender).await };
+        let handle = async {
+            while let Some(action) = receiver.recv().await {
+                broker.handle(action).await;
             }
+        };
+        tokio::join!(parse, handle);
+    }
+
+    #[tokio::test]
+    async fn queries_are_answered_with_info_lines() {
+        let mut broker = Broker::new();
+        let (bot, _bot_peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, bot, "NAME:frog").await;
+        let (id, mut peer) = join_client(&mut broker).await;
+
+        client_line(&mut broker, id, "STATUS:ghost").await;
+        assert_eq!(
+            peer.lines().await,
+            vec![
+                "INFO:bot ghost not connected",
+                "INFO:no referee connected",
+                INFO_END
+            ]
+        );
+
+        client_line(&mut broker, id, "STATUS:frog").await;
+        let lines = peer.lines().await;
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("INFO:bot frog at 127.0.0.1:"));
+        assert_eq!(lines[2], INFO_END);
+
+        client_line(&mut broker, id, "WHOAMI").await;
+        let lines = peer.lines().await;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("INFO:client (unnamed) at 127.0.0.1:"));
+        assert_eq!(lines[1], INFO_END);
+
+        client_line(&mut broker, id, "REFEREE").await;
+        peer.received().await;
+        client_line(&mut broker, id, "LIST").await;
+        let lines = peer.lines().await;
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("INFO:bot frog "));
+        assert!(lines[1].starts_with("INFO:client (unnamed) [REFEREE] "));
+        assert_eq!(lines[2..], ["INFO:referee connected", INFO_END]);
+    }
+
+    #[tokio::test]
+    async fn whoami_lists_the_bots_of_the_client() {
+        let mut broker = Broker::new();
+        let (id, mut peer) = join_client(&mut broker).await;
+        client_line(&mut broker, id, "NAME:frog").await;
+        peer.received().await;
+
+        client_line(&mut broker, id, "WHOAMI").await;
+        let lines = peer.lines().await;
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("INFO:client frog at "));
+        assert_eq!(lines[1..], ["INFO:bot frog not connected", INFO_END]);
+    }
+
+    #[tokio::test]
+    async fn only_referees_can_start_and_stop_bots() {
+        let mut broker = Broker::new();
+        let (bot, mut bot_peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, bot, "NAME:frog").await;
+        bot_peer.received().await;
+        let (id, mut peer) = join_client(&mut broker).await;
+        let (spectator, mut spectator_peer) = join_client(&mut broker).await;
+        client_line(&mut broker, spectator, "SPECTATOR").await;
+        spectator_peer.received().await;
+
+        client_line(&mut broker, id, "z").await;
+        client_line(&mut broker, spectator, "x").await;
+        let error = |lines: Vec<String>| {
+            assert_eq!(lines.len(), 1);
+            let error = lines[0].strip_prefix(ERROR_PREFIX).unwrap().to_string();
+            error.rsplit_once(':').unwrap().1.to_string()
+        };
+        assert_eq!(
+            error(peer.lines().await),
+            "only referees can send referee command STOP"
+        );
+        assert_eq!(
+            error(spectator_peer.lines().await),
+            "only referees can send referee command START"
+        );
+        assert_eq!(bot_peer.commands().await, "");
+
+        client_line(&mut broker, id, "REFEREE").await;
+        client_line(&mut broker, id, "z").await;
+        assert_eq!(bot_peer.commands().await, "z");
+    }
+
+    fn bot_source<'a>(bot: &'a str, team: Option<&'a str>) -> EventSource<'a> {
+        EventSource {
+            bot: Some(bot),
+            team,
+            arena: None,
         }
     }
 
-    Ok(())
-}
+    #[test]
+    fn name_patterns_match_wildcards() {
+        assert!(name_matches("*", ""));
+        assert!(name_matches("frog*", "frog.v2"));
+        assert!(name_matches("?oad", "toad"));
+        assert!(name_matches("*o*", "robot"));
+        assert!(!name_matches("frog", "frogs"));
+        assert!(!name_matches("?", ""));
+    }
 
-async fn cmd_client(
-    client_port: u16,
-    address: String,
-    name: String,
-    is_referee: bool,
-    is_private: bool,
-) -> Result<(), Box<dyn Error>> {
-    let addr = format!("{}:{}", &address, client_port);
-    let stream = TcpStream::connect(&addr).await?;
-    let (log_stream, mut cmd_stream) = stream.into_split();
+    #[test]
+    fn default_subscription_skips_telemetry_only() {
+        let subscription = Subscription::default();
+        let source = bot_source("frog", None);
+        assert!(subscription.accepts(EventKind::Logs, &source, Some(LogLevel::Trace)));
+        assert!(subscription.accepts(EventKind::Referee, &EventSource::default(), None));
+        assert!(!subscription.accepts(EventKind::Telemetry, &source, None));
+    }
 
-    spawn(async move {
-        let log_reader = BufReader::new(log_stream);
-        let mut lines = log_reader.lines();
+    #[test]
+    fn subscription_filters_bots_levels_and_events() {
+        let mut subscription = Subscription::default();
+        subscription.update("bots=frog*, toad").unwrap();
+        subscription.update("level=warn").unwrap();
+        subscription.update("events=logs,telemetry").unwrap();
+
+        let frog = bot_source("frog.v2", None);
+        assert!(subscription.accepts(EventKind::Logs, &frog, Some(LogLevel::Error)));
+        assert!(!subscription.accepts(EventKind::Logs, &frog, Some(LogLevel::Info)));
+        assert!(subscription.accepts(EventKind::Telemetry, &frog, None));
+        assert!(!subscription.accepts(EventKind::Commands, &frog, None));
+        assert!(!subscription.accepts(EventKind::Logs, &bot_source("newt", None), None));
+        assert_eq!(
+            subscription.to_string(),
+            "bots frog*,toad teams * arenas * level warn events logs,telemetry"
+        );
+    }
+
+    #[test]
+    fn subscription_rejects_invalid_updates() {
+        let mut subscription = Subscription::default();
+        for text in [
+            "bots",
+            "bots=",
+            "level=loud",
+            "events=logs,gossip",
+            "colour=red",
+        ] {
+            assert!(subscription.update(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn log_level_tags_are_split_from_lines() {
+        assert_eq!(
+            LogLevel::split_tag("[warn] battery low"),
+            (Some(LogLevel::Warn), "battery low")
+        );
+        assert_eq!(
+            LogLevel::split_tag("[ error ]stalled"),
+            (Some(LogLevel::Error), "stalled")
+        );
+        assert_eq!(LogLevel::split_tag("[loud] hi"), (None, "[loud] hi"));
+        assert_eq!(LogLevel::split_tag("no tag"), (None, "no tag"));
+        assert!(LogLevel::Error < LogLevel::Trace);
+    }
+
+    #[test]
+    fn referee_lines_are_told_apart() {
+        for line in [
+            "x",
+            "Z",
+            "ANNOUNCE:hi",
+            "ANNOUNCE_BOTS:hi",
+            "MATCH:frog,toad",
+            "SCORE:frog:1",
+        ] {
+            assert!(is_referee_line(line), "{}", line);
+        }
+        for line in ["a", "xz", "NAME:frog", "TO:frog:a", "QUEUE:60:a", "LIST"] {
+            assert!(!is_referee_line(line), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_reconnect_without_replaying_referee_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (input_sender, input) = mpsc::channel(8);
+        let (events, mut event_receiver) = mpsc::unbounded_channel();
+        let claims = vec!["NAME:frog".to_string()];
+        let client = spawn(client_connection(addr, claims, input, events));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "NAME:frog");
+        // Lines typed while the broker is away are buffered
+        drop(lines);
         loop {
-            match lines.next_line().await {
-                Ok(line) => {
-                    if let Some(line) = line {
-                        if line != PING_LINE {
-                            println!("{}", line);
-                        }
-                    } else {
-                        println!("{}: logs terminated", Local::now());
-                        break;
-                    }
-                }
-                Err(err) => {
-                    println!("{}: error reading logs: {}", Local::now(), err);
-                    break;
-                }
+            match event_receiver.recv().await.unwrap() {
+                ClientEvent::Status(status) if status.contains("lost connection") => break,
+                _ => {}
             }
         }
-    });
+        for line in ["z", "a", "ANNOUNCE:hi"] {
+            input_sender.send(line.to_string()).await.unwrap();
+        }
 
-    if is_private {
-        let line = format!("NAME:{}\n", name);
-        cmd_stream.write_all(line.as_bytes()).await?;
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "NAME:frog");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "a");
+        drop(input_sender);
+        client.await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap(), None);
+
+        let mut statuses = Vec::new();
+        while let Ok(event) = event_receiver.try_recv() {
+            if let ClientEvent::Status(status) = event {
+                statuses.push(status);
+            }
+        }
+        for line in ["z", "ANNOUNCE:hi"] {
+            let note = format!("not replaying '{}' typed while disconnected", line);
+            assert!(
+                statuses.iter().any(|status| status.starts_with(&note)),
+                "{}",
+                line
+            );
+        }
     }
 
-    if is_referee {
-        cmd_stream.write_all("REFEREE\n".as_bytes()).await?;
+    #[tokio::test]
+    async fn queued_commands_wait_for_their_bot_in_order() {
+        let mut broker = Broker::new();
+        let (frog, mut frog_peer) = join_client(&mut broker).await;
+        client_line(&mut broker, frog, "NAME:frog").await;
+        let (toad, _toad_peer) = join_client(&mut broker).await;
+        client_line(&mut broker, toad, "NAME:toad").await;
+        for line in ["QUEUE:60:a", "QUEUE:0:b", "QUEUE:3600:c"] {
+            client_line(&mut broker, frog, line).await;
+        }
+        client_line(&mut broker, toad, "QUEUE:60:d").await;
+        let lines = frog_peer.lines().await;
+        let queued = lines
+            .iter()
+            .filter(|line| line.contains(":frog:private command '"))
+            .filter(|line| line.contains(" (bot not connected, queued until "));
+        assert_eq!(queued.count(), 3);
+
+        let (bot, mut bot_peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, bot, "NAME:frog").await;
+        assert_eq!(bot_peer.commands().await, "ac");
+        let lines = frog_peer.lines().await;
+        let outcomes: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.split_once(":frog:").map(|(_, outcome)| outcome))
+            .collect();
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes[0].starts_with("delivered private command 'a' queued by frog at "));
+        assert_eq!(outcomes[1], "queued private command 'b' from frog expired");
+        assert!(outcomes[2].starts_with("delivered private command 'c' queued by frog at "));
+        assert!(!broker.queued_commands.contains_key("frog"));
+        assert!(broker.queued_commands.contains_key("toad"));
     }
 
-    let stdin_reader = BufReader::new(tokio::io::stdin());
-    let mut stdin_lines = stdin_reader.lines();
-    loop {
-        match stdin_lines.next_line().await {
-            Ok(line) => {
-                if let Some(mut line) = line {
-                    line.push('\n');
-                    cmd_stream.write_all(line.as_bytes()).await?;
-                } else {
-                    println!("stdin terminated, exiting");
-                    break;
-                }
+    #[tokio::test]
+    async fn commands_are_queued_for_an_hour_at_most() {
+        let mut broker = Broker::new();
+        let (id, mut peer) = join_client(&mut broker).await;
+        client_line(&mut broker, id, "NAME:frog").await;
+        peer.received().await;
+
+        client_line(&mut broker, id, "QUEUE:3601:a").await;
+        let lines = peer.lines().await;
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with(ERROR_PREFIX));
+        assert!(lines[0].ends_with(":commands cannot be queued for more than 3600s"));
+        assert!(broker.queued_commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_commands_are_dropped_while_waiting() {
+        let mut broker = Broker::new();
+        let (id, mut peer) = join_client(&mut broker).await;
+        client_line(&mut broker, id, "NAME:frog").await;
+        client_line(&mut broker, id, "QUEUE:0:a").await;
+        client_line(&mut broker, id, "QUEUE:60:b").await;
+        peer.received().await;
+
+        broker.expire_queued_commands().await;
+        let lines = peer.lines().await;
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(":frog:queued private command 'a' from frog expired"));
+        assert_eq!(broker.queued_commands["frog"].len(), 1);
+    }
+
+    /// The session token sent to a bot, among what it received
+    fn session_token(received: &str) -> String {
+        let start = received.find("#SESSION:").unwrap() + "#SESSION:".len();
+        received[start..].lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions_evict_the_stale_connection() {
+        let mut broker = Broker::new();
+        let (referee, mut referee_peer) = join_client(&mut broker).await;
+        for line in ["REFEREE", "ARENA:north", "MATCH:frog,toad"] {
+            client_line(&mut broker, referee, line).await;
+        }
+        let (client, mut client_peer) = join_client(&mut broker).await;
+        client_line(&mut broker, client, "NAME:frog").await;
+        let (stale, mut stale_peer) = join_bot(&mut broker).await;
+        for line in ["LINES", "TEAM:green", "NAME:frog"] {
+            bot_line(&mut broker, stale, line).await;
+        }
+        let token = session_token(&stale_peer.received().await);
+        referee_peer.received().await;
+        client_peer.received().await;
+
+        // The old connection is still there, as the broker did not notice it is gone
+        let (id, mut peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, id, &format!("RESUME:{}", token)).await;
+        assert!(stale_peer.is_closed().await);
+        assert!(!broker.bots.contains_key(&stale));
+        assert_eq!(broker.bots[&id].name.as_deref(), Some("frog"));
+        assert_eq!(broker.bots[&id].team.as_deref(), Some("green"));
+        assert_eq!(broker.sessions[&token].bot_id, id);
+        let lines = client_peer.lines().await;
+        assert!(lines
+            .iter()
+            .any(|line| line.contains(":frog:reconnected after ")));
+
+        client_line(&mut broker, client, "a").await;
+        client_line(&mut broker, referee, "x").await;
+        assert_eq!(peer.commands().await, "ax");
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_refused() {
+        let mut broker = Broker::new();
+        let (id, mut peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, id, "RESUME:01ARZ3NDEKTSV4RRFFQ69G5FAV").await;
+        assert_eq!(peer.received().await, "#SESSION_UNKNOWN\n");
+        assert_eq!(broker.bots[&id].name, None);
+
+        // A session expires some time after its bot went away
+        let (old, mut old_peer) = join_bot(&mut broker).await;
+        for line in ["LINES", "NAME:frog"] {
+            bot_line(&mut broker, old, line).await;
+        }
+        let token = session_token(&old_peer.received().await);
+        broker.handle(BrokerAction::BotLeave { id: old }).await;
+        let session = broker.sessions.get_mut(&token).unwrap();
+        let expired_at = Local::now() - chrono::Duration::from_std(SESSION_TIMEOUT).unwrap();
+        session.disconnected_at = Some(expired_at);
+        broker.expire_sessions();
+        bot_line(&mut broker, id, &format!("RESUME:{}", token)).await;
+        assert!(peer.received().await.ends_with("#SESSION_UNKNOWN\n"));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_only_sent_to_bots_that_read_lines() {
+        let mut broker = Broker::new();
+        let (id, mut peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, id, "NAME:frog").await;
+        assert!(!peer.received().await.contains('#'));
+        assert!(broker.sessions.is_empty());
+
+        bot_line(&mut broker, id, "LINES").await;
+        let token = session_token(&peer.received().await);
+        assert_eq!(broker.sessions[&token].name, "frog");
+    }
+
+    #[test]
+    fn team_subscription_needs_a_matching_team() {
+        let mut subscription = Subscription::default();
+        assert!(subscription.accepts(EventKind::Logs, &bot_source("frog", None), None));
+        subscription.update("teams=green").unwrap();
+        assert!(subscription.accepts(EventKind::Logs, &bot_source("frog", Some("green")), None));
+        assert!(!subscription.accepts(EventKind::Logs, &bot_source("toad", Some("red")), None));
+        assert!(!subscription.accepts(EventKind::Logs, &bot_source("newt", None), None));
+        // Events about no bot in particular are not filtered by team
+        assert!(subscription.accepts(EventKind::Referee, &EventSource::default(), None));
+    }
+
+    #[tokio::test]
+    async fn kicked_bots_are_disconnected() {
+        let no_limit = RateLimit {
+            rate: 0.0,
+            burst: 0.0,
+            policy: OverflowPolicy::Drop,
+        };
+        let (sender, mut receiver) = mpsc::channel(32);
+        let bot_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bot_addr = bot_listener.local_addr().unwrap();
+        spawn(broker_bot_listener(bot_listener, sender.clone(), no_limit));
+        let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client_listener.local_addr().unwrap();
+        spawn(broker_cmd_listener(
+            client_listener,
+            sender.clone(),
+            no_limit,
+        ));
+        spawn(async move {
+            let mut broker = Broker::new();
+            while let Some(action) = receiver.recv().await {
+                broker.handle(action).await;
             }
-            Err(err) => {
-                println!("error reading from stdin, exiting: {}", err);
+        });
+
+        let mut client = Peer {
+            stream: TcpStream::connect(client_addr).await.unwrap(),
+        };
+        let mut bot = Peer {
+            stream: TcpStream::connect(bot_addr).await.unwrap(),
+        };
+        bot.stream.write_all(b"NAME:frog\nhello\n").await.unwrap();
+        let lines = client.lines().await;
+        assert!(lines.iter().any(|line| line.ends_with(":frog:hello")));
+
+        let line = "kick bot frog".to_string();
+        sender.send(BrokerAction::Console { line }).await.unwrap();
+        assert!(bot.is_closed().await);
+        let lines = client.lines().await;
+        assert!(lines
+            .iter()
+            .any(|line| line.ends_with(":frog:kicked by the operator")));
+
+        // The connection is closed, not only its writing side
+        let mut is_reset = false;
+        for _ in 0..10 {
+            if bot.stream.write_all(b"still here\n").await.is_err() {
+                is_reset = true;
                 break;
             }
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
+        assert!(is_reset);
     }
 
-    Ok(())
-}
-
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let global_args = Arguments::parse();
+    #[tokio::test]
+    async fn lines_from_bots_that_are_gone_are_ignored() {
+        let mut broker = Broker::new();
+        let (client, mut client_peer) = join_client(&mut broker).await;
+        client_line(&mut broker, client, "NAME:frog").await;
+        let (id, _peer) = join_bot(&mut broker).await;
+        bot_line(&mut broker, id, "NAME:frog").await;
+        let line = "kick bot frog".to_string();
+        broker.handle(BrokerAction::Console { line }).await;
+        client_peer.received().await;
 
-    match global_args.action {
-        SubCommand::Broker(args) => {
-            broker(global_args.bot_port, global_args.client_port, args).await
-        }
-        SubCommand::Referee(args) => {
-            cmd_client(
-                global_args.client_port,
-                args.address,
-                "REFEREE".to_string(),
-                true,
-                false,
-            )
-            .await
+        for line in ["still here", "NAME:toad", "TEAM:green", "ACK:z", "LINES"] {
+            bot_line(&mut broker, id, line).await;
         }
-        SubCommand::Cmd(args) => {
-            cmd_client(
-                global_args.client_port,
-                args.address,
-                args.name,
-                args.referee,
-                true,
-            )
-            .await
+        assert_eq!(client_peer.received().await, "");
+        assert!(broker.bots.is_empty());
+        assert!(broker.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_stop_acks_until_the_timeout() {
+        let mut broker = Broker::new();
+        let (_client, mut client_peer) = join_client(&mut broker).await;
+        let (frog, mut frog_peer) = join_bot(&mut broker).await;
+        let (_toad, mut toad_peer) = join_bot(&mut broker).await;
+
+        // Only one of the bots acknowledges STOP
+        let (sender, mut receiver) = mpsc::channel(8);
+        broker_bot_line(frog, "ACK:z".to_string(), &sender).await;
+        let started_at = Instant::now();
+        broker.shutdown(&mut receiver).await;
+        assert!(started_at.elapsed() >= SHUTDOWN_ACK_TIMEOUT);
+        assert_eq!(frog_peer.commands().await, "z");
+        assert_eq!(toad_peer.commands().await, "z");
+
+        let lines = client_peer.lines().await;
+        let notice = lines.last().unwrap();
+        assert!(notice.starts_with(ANNOUNCE_PREFIX));
+        assert!(notice.ends_with(":broker:shutting down, 1 of 2 bots acknowledged STOP"));
+        drop(broker);
+        assert!(client_peer.is_closed().await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_when_every_bot_acknowledged_stop() {
+        let mut broker = Broker::new();
+        let (_client, mut client_peer) = join_client(&mut broker).await;
+        let (sender, mut receiver) = mpsc::channel(8);
+        let mut bot_peers = Vec::new();
+        for _ in 0..2 {
+            let (id, peer) = join_bot(&mut broker).await;
+            broker_bot_line(id, "ACK:z".to_string(), &sender).await;
+            bot_peers.push(peer);
         }
-    }?;
 
-    Ok(())
+        let started_at = Instant::now();
+        broker.shutdown(&mut receiver).await;
+        assert!(started_at.elapsed() < SHUTDOWN_ACK_TIMEOUT);
+        let lines = client_peer.lines().await;
+        assert!(lines
+            .last()
+            .unwrap()
+            .ends_with(":broker:shutting down, 2 of 2 bots acknowledged STOP"));
+    }
+
+    #[test]
+    fn broker_time_stamps_are_split_from_lines() {
+        assert_eq!(split_stamp("@1500 [warn] low"), (Some(1500), "[warn] low"));
+        assert_eq!(split_stamp("@soon hello"), (None, "@soon hello"));
+        assert_eq!(split_stamp("@1500"), (None, "@1500"));
+        assert_eq!(split_stamp("plain"), (None, "plain"));
+    }
+
+    #[tokio::test]
+    async fn latency_is_measured_from_the_last_ping() {
+        let mut broker = Broker::new();
+        let (id, _peer) = join_bot(&mut broker).await;
+        broker.measure_latency().await;
+        // The PONG to this ping never comes
+        let lost_ping = Instant::now() - Duration::from_secs(10);
+        broker.bots.get_mut(&id).unwrap().ping_sent = Some(lost_ping);
+
+        broker.measure_latency().await;
+        broker
+            .handle(BrokerAction::BotPong {
+                id,
+                time: Instant::now(),
+            })
+            .await;
+        let bot = &broker.bots[&id];
+        assert!(bot.latency.unwrap() < Duration::from_secs(1));
+        assert_eq!(bot.ping_sent, None);
+    }
+
+    #[test]
+    fn ipv6_socket_addresses_are_bracketed() {
+        assert_eq!(socket_address("::1", 9001), "[::1]:9001");
+        assert_eq!(socket_address("[::1]", 9001), "[::1]:9001");
+        assert_eq!(socket_address("0.0.0.0", 9001), "0.0.0.0:9001");
+        assert_eq!(socket_address("localhost", 9001), "localhost:9001");
+    }
+
+    #[tokio::test]
+    async fn addresses_resolve_with_their_port() {
+        let addr = resolve_address("::1", 9001).await.unwrap();
+        assert_eq!(addr, "[::1]:9001".parse().unwrap());
+        let addr = resolve_address("127.0.0.1", 9002).await.unwrap();
+        assert_eq!(addr, "127.0.0.1:9002".parse().unwrap());
+    }
+
+    #[test]
+    fn line_framing_is_a_control_line() {
+        for line in [
+            "LINES",
+            "PONG",
+            "NAME:frog",
+            "RESUME:01H",
+            "TEAM:green",
+            "SYNC:12",
+        ] {
+            assert!(is_bot_control_line(line), "{}", line);
+        }
+        assert!(!is_bot_control_line("LINES please"));
+        assert!(!is_bot_control_line("[info] NAME:frog"));
+    }
 }
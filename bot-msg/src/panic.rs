@@ -0,0 +1,381 @@
+//! Decoding of the panic dumps left by `rp2040_panic_usb_boot`
+//!
+//! When the firmware panics it writes the panic message as text at the start of the
+//! XIP cache RAM and reboots the RP2040 in USB boot mode, where that RAM survives.
+//! `bot/get-panic.sh` saves it with picotool from `PANIC_RAM_START` for
+//! `PANIC_RAM_SIZE` bytes. The text ends with a NUL byte, and what follows is whatever
+//! the cache held before. The text is the panic info as formatted by `core`, like
+//! `panicked at src/main.rs:42:9:\n<message>`, or `panicked at '<message>', src/main.rs:42:9`
+//! with older compilers.
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use addr2line::gimli::{self, EndianSlice, RunTimeEndian};
+use chrono::{DateTime, Local};
+use object::{Object, ObjectSection, SectionKind, SymbolMap, SymbolMapName};
+
+use crate::{
+    recording::{RecordedEvent, Recorder},
+    EventKind, LogLevel, LOG_PREFIX,
+};
+
+pub const PANIC_RAM_START: u32 = 0x1500_0000;
+pub const PANIC_RAM_SIZE: usize = 0x4000;
+
+const PANIC_PREFIX: &str = "panicked at ";
+
+/// Functions found at the panic location are listed up to this many
+const MAX_LOCATION_FUNCTIONS: usize = 4;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PanicLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: Option<u32>,
+}
+
+impl PanicLocation {
+    /// Parses `<file>:<line>[:<column>]`
+    fn decode(text: &str) -> Option<Self> {
+        let mut parts = text.trim().rsplitn(3, ':');
+        let last = parts.next()?.parse::<u32>().ok()?;
+        let before = parts.next()?;
+        match before.parse::<u32>() {
+            Ok(line) => Some(Self {
+                file: parts.next()?.to_string(),
+                line,
+                column: Some(last),
+            }),
+            Err(_) => Some(Self {
+                file: match parts.next() {
+                    Some(file) => format!("{}:{}", file, before),
+                    None => before.to_string(),
+                },
+                line: last,
+                column: None,
+            }),
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self.column {
+            Some(column) => format!("{}:{}:{}", self.file, self.line, column),
+            None => format!("{}:{}", self.file, self.line),
+        }
+    }
+}
+
+/// The panic text found in a dump
+#[derive(Clone, PartialEq, Debug)]
+pub struct PanicDump {
+    pub text: String,
+    pub message: Option<String>,
+    pub location: Option<PanicLocation>,
+}
+
+impl PanicDump {
+    /// Extracts the panic text from the start of the dump, if there is any
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let end = data
+            .iter()
+            .position(|&byte| {
+                byte == 0 || byte == 0xff || (byte < b' ' && !b"\t\r\n".contains(&byte))
+            })
+            .unwrap_or(data.len());
+        let text = String::from_utf8_lossy(&data[..end]).trim_end().to_string();
+        if text.is_empty() {
+            return None;
+        }
+
+        let (message, location) = match text.strip_prefix(PANIC_PREFIX) {
+            // The quote that ends the message cannot be the one that starts it
+            Some(rest) if rest.starts_with('\'') => match rest.rfind("', ").filter(|&i| i > 0) {
+                Some(index) => (
+                    Some(rest[1..index].to_string()),
+                    PanicLocation::decode(&rest[index + 3..]),
+                ),
+                None => (Some(rest[1..].to_string()), None),
+            },
+            Some(rest) => match rest.split_once(":\n") {
+                Some((location, message)) => {
+                    (Some(message.to_string()), PanicLocation::decode(location))
+                }
+                None => (None, PanicLocation::decode(rest.trim_end_matches(':'))),
+            },
+            None => (None, None),
+        };
+        Some(Self {
+            text,
+            message,
+            location,
+        })
+    }
+
+    /// Addresses written in the panic text, like `0x10001a2c`
+    fn addresses(&self) -> Vec<u64> {
+        self.text
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter_map(|word| word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")))
+            .filter_map(|digits| u64::from_str_radix(digits, 16).ok())
+            .collect()
+    }
+}
+
+/// Symbols and debug information of the firmware ELF
+struct Firmware<'data> {
+    symbols: SymbolMap<SymbolMapName<'data>>,
+    context: addr2line::Context<EndianSlice<'data, RunTimeEndian>>,
+    code: Vec<(u64, u64)>,
+}
+
+impl<'data> Firmware<'data> {
+    fn load(data: &'data [u8]) -> Result<Self, Box<dyn Error>> {
+        let file = object::File::parse(data)?;
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let section = file
+                .section_by_name(id.name())
+                .and_then(|section| section.data().ok())
+                .unwrap_or_default();
+            Ok(EndianSlice::new(section, endian))
+        })?;
+        let code = file
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .map(|section| (section.address(), section.address() + section.size()))
+            .collect();
+        Ok(Self {
+            symbols: file.symbol_map(),
+            context: addr2line::Context::from_dwarf(dwarf)?,
+            code,
+        })
+    }
+
+    fn is_code(&self, address: u64) -> bool {
+        self.code
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&address))
+    }
+
+    /// The functions at an address, innermost first when they are inlined
+    fn functions(&self, address: u64) -> Vec<String> {
+        let mut functions = Vec::new();
+        if let Ok(mut frames) = self.context.find_frames(address).skip_all_loads() {
+            while let Ok(Some(frame)) = frames.next() {
+                if let Some(Ok(name)) = frame.function.as_ref().map(|name| name.demangle()) {
+                    functions.push(name.into_owned());
+                }
+            }
+        }
+        if functions.is_empty() {
+            if let Some(symbol) = self.symbols.get(address) {
+                functions.push(format!(
+                    "{}+{:#x}",
+                    rustc_demangle::demangle(symbol.name()),
+                    address - symbol.address()
+                ));
+            }
+        }
+        functions
+    }
+
+    /// The function at an address, with the ones it is inlined into
+    fn function(&self, address: u64) -> String {
+        match self.functions(address).split_first() {
+            Some((function, [])) => function.clone(),
+            Some((function, callers)) => {
+                format!("{} (inlined into {})", function, callers.join(" < "))
+            }
+            None => "unknown function".to_string(),
+        }
+    }
+
+    /// Describes a code address, clearing the Thumb bit of return addresses
+    fn describe(&self, address: u64) -> String {
+        let address = address & !1;
+        let mut description = self.function(address);
+        if let Ok(Some(location)) = self.context.find_location(address) {
+            if let (Some(file), Some(line)) = (location.file, location.line) {
+                description.push_str(&format!(" at {}:{}", file, line));
+            }
+        }
+        description
+    }
+
+    /// Functions with code generated for a source location
+    fn location_functions(&self, location: &PanicLocation) -> Vec<String> {
+        let file = Path::new(&location.file);
+        let mut addresses = Vec::new();
+        for &(start, end) in self.code.iter() {
+            let Ok(rows) = self.context.find_location_range(start, end) else {
+                continue;
+            };
+            for (address, _, row) in rows {
+                let matches_file = row.file.is_some_and(|row_file| {
+                    let row_file = Path::new(row_file);
+                    row_file == file || (file.is_relative() && row_file.ends_with(file))
+                });
+                if matches_file && row.line == Some(location.line) {
+                    let exact = location.column.is_none() || row.column == location.column;
+                    addresses.push((!exact, address));
+                }
+            }
+        }
+        // Rows on the exact column come first, as they are the panic call itself
+        addresses.sort();
+
+        let mut functions: Vec<String> = Vec::new();
+        for (_, address) in addresses {
+            let function = self.function(address);
+            if !functions.contains(&function) {
+                functions.push(function);
+            }
+            if functions.len() == MAX_LOCATION_FUNCTIONS {
+                break;
+            }
+        }
+        functions
+    }
+}
+
+/// Decodes a panic dump saved with picotool, as a readable report
+pub fn decode(path: &Path, elf: Option<&Path>) -> Result<String, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    let mut report = vec![format!(
+        "panic dump {} ({} bytes from {:#010x})",
+        path.display(),
+        data.len(),
+        PANIC_RAM_START
+    )];
+    if data.len() > PANIC_RAM_SIZE {
+        report.push(format!(
+            "warning: the dump is larger than the {} bytes of the XIP cache RAM",
+            PANIC_RAM_SIZE
+        ));
+    }
+    let Some(dump) = PanicDump::decode(&data) else {
+        report.push("no panic message found".to_string());
+        return Ok(report.join("\n"));
+    };
+
+    if dump.message.is_none() && dump.location.is_none() {
+        report.push(format!("unrecognised text: {}", dump.text));
+    } else {
+        let message = dump.message.as_deref().unwrap_or("(no message)");
+        let mut lines = message.lines();
+        report.push(format!("message: {}", lines.next().unwrap_or_default()));
+        report.extend(lines.map(|line| format!("  {}", line)));
+    }
+
+    let elf_data = elf.map(fs::read).transpose()?;
+    let firmware = elf_data.as_deref().map(Firmware::load).transpose()?;
+    if let Some(location) = dump.location.as_ref() {
+        report.push(format!("location: {}", location.encode()));
+        if let Some(firmware) = firmware.as_ref() {
+            let functions = firmware.location_functions(location);
+            if functions.is_empty() {
+                report.push("  no code found for this location in the firmware".to_string());
+            }
+            report.extend(
+                functions
+                    .iter()
+                    .map(|function| format!("  in {}", function)),
+            );
+        }
+    }
+    if let Some(firmware) = firmware.as_ref() {
+        for address in dump.addresses() {
+            if firmware.is_code(address & !1) {
+                report.push(format!(
+                    "address {:#010x}: {}",
+                    address,
+                    firmware.describe(address)
+                ));
+            }
+        }
+    }
+    Ok(report.join("\n"))
+}
+
+/// Appends a report to a recording, as errors logged by the bot, one line each
+pub fn attach(
+    recording: PathBuf,
+    bot: &str,
+    dump: &Path,
+    report: &str,
+) -> Result<(), Box<dyn Error>> {
+    // The dump is saved right after the panic, more or less
+    let time = fs::metadata(dump)
+        .and_then(|metadata| metadata.modified())
+        .map(DateTime::<Local>::from)
+        .unwrap_or_else(|_| Local::now());
+    let level = LogLevel::Error.encode();
+    let mut recorder = Recorder::start(recording)?;
+    for line in report.lines() {
+        recorder.record(&RecordedEvent {
+            time,
+            kind: EventKind::Logs.encode().to_string(),
+            bot: Some(bot.to_string()),
+            team: None,
+            arena: None,
+            level: Some(level.to_string()),
+            message: format!("{}{}:{}:{}:{}", LOG_PREFIX, level, time, bot, line),
+        })?;
+    }
+    recorder.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_locations_are_parsed() {
+        let location = PanicLocation::decode("src/main.rs:42:9").unwrap();
+        assert_eq!(location.file, "src/main.rs");
+        assert_eq!((location.line, location.column), (42, Some(9)));
+        assert_eq!(location.encode(), "src/main.rs:42:9");
+
+        let location = PanicLocation::decode("src/main.rs:42").unwrap();
+        assert_eq!((location.line, location.column), (42, None));
+        let location = PanicLocation::decode("C:\\bot\\main.rs:42").unwrap();
+        assert_eq!(location.file, "C:\\bot\\main.rs");
+        assert_eq!(PanicLocation::decode("src/main.rs"), None);
+    }
+
+    #[test]
+    fn panic_dumps_are_parsed_in_both_formats() {
+        let mut data = b"panicked at src/motor.rs:17:5:\nstall at 0x10001a2c\0garbage".to_vec();
+        data.resize(64, 0xff);
+        let dump = PanicDump::decode(&data).unwrap();
+        assert_eq!(dump.message.as_deref(), Some("stall at 0x10001a2c"));
+        assert_eq!(dump.location, PanicLocation::decode("src/motor.rs:17:5"));
+        assert_eq!(dump.addresses(), vec![0x1000_1a2c]);
+
+        let dump = PanicDump::decode(b"panicked at 'no sensor', src/main.rs:8:1\0").unwrap();
+        assert_eq!(dump.message.as_deref(), Some("no sensor"));
+        assert_eq!(dump.location, PanicLocation::decode("src/main.rs:8:1"));
+
+        let dump = PanicDump::decode(b"panicked at ', x\0").unwrap();
+        assert_eq!(dump.message.as_deref(), Some(", x"));
+        assert_eq!(dump.location, None);
+
+        let dump = PanicDump::decode(b"hello\xff").unwrap();
+        assert_eq!((dump.message, dump.location), (None, None));
+    }
+
+    #[test]
+    fn empty_dumps_have_no_panic() {
+        assert_eq!(PanicDump::decode(b""), None);
+        assert_eq!(PanicDump::decode(&[0xff; 16]), None);
+        assert_eq!(PanicDump::decode(b"\0panicked at x:1"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// One event sent to clients, as stored in a recording
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedEvent {
+    pub time: DateTime<Local>,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arena: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    pub message: String,
+}
+
+/// Appends the broker events to a file, one JSON object per line
+pub struct Recorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn start(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, event: &RecordedEvent) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A recording file name based on the current time
+pub fn default_recording_path() -> PathBuf {
+    PathBuf::from(format!(
+        "bot-msg-{}.jsonl",
+        Local::now().format("%Y%m%d-%H%M%S")
+    ))
+}
+
+/// Reads the events of a recording, along with the number of lines that are not events
+pub fn read_recording(path: &Path) -> Result<(Vec<RecordedEvent>, usize), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    let mut invalid_lines = 0;
+    for line in reader.lines() {
+        match serde_json::from_str::<RecordedEvent>(&line?) {
+            Ok(event) => events.push(event),
+            Err(_) => invalid_lines += 1,
+        }
+    }
+    Ok((events, invalid_lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recordings_read_back_skipping_invalid_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let event = RecordedEvent {
+            time: Local::now(),
+            kind: "logs".to_string(),
+            bot: Some("frog".to_string()),
+            team: None,
+            arena: Some("north".to_string()),
+            level: Some("warn".to_string()),
+            message: "LOG:warn:battery low".to_string(),
+        };
+        let mut recorder = Recorder::start(path.clone()).unwrap();
+        recorder.record(&event).unwrap();
+        recorder.flush().unwrap();
+        drop(recorder);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+        // Recording again appends instead of starting over
+        let mut recorder = Recorder::start(path.clone()).unwrap();
+        recorder.record(&event).unwrap();
+        recorder.flush().unwrap();
+
+        let (events, invalid_lines) = read_recording(&path).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(invalid_lines, 1);
+        assert_eq!(events[1].bot.as_deref(), Some("frog"));
+        assert_eq!(events[1].team, None);
+        assert_eq!(events[1].message, event.message);
+    }
+}
@@ -0,0 +1,632 @@
+//! Tournament results, from the broker state file or from a recording
+//!
+//! The state file has every match played. A recording only has the referee events,
+//! but each of them describes the match it is about (`match 2: frog vs toad, running
+//! for 12s (60s limit), score frog 1 toad 0`), which is enough to rebuild the bouts.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+
+use crate::{
+    arena::{Match, TournamentState},
+    name_matches,
+    recording::RecordedEvent,
+    EventKind, RefereeCommand, TIME_FORMAT,
+};
+
+/// A match played in an arena
+pub struct Bout {
+    pub arena: String,
+    pub result: Match,
+}
+
+impl Bout {
+    pub fn is_finished(&self) -> bool {
+        self.result.ended_at.is_some()
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        match (self.result.started_at, self.result.ended_at) {
+            (Some(started_at), Some(ended_at)) => (ended_at - started_at).to_std().ok(),
+            _ => None,
+        }
+    }
+
+    fn scores(&self) -> Vec<i64> {
+        self.result
+            .bots
+            .iter()
+            .map(|bot| self.result.scores.get(bot).copied().unwrap_or_default())
+            .collect()
+    }
+
+    /// The bot with the most points in a finished bout, none for a draw
+    pub fn winner(&self) -> Option<&str> {
+        if !self.is_finished() {
+            return None;
+        }
+        let best = self.result.scores.values().max()?;
+        let mut leaders = self
+            .result
+            .scores
+            .iter()
+            .filter(|(_, score)| *score == best);
+        match (leaders.next(), leaders.next()) {
+            (Some((bot, _)), None) => Some(bot),
+            _ => None,
+        }
+    }
+}
+
+/// The bouts recorded in the broker state file that have started
+pub fn state_bouts(state: &TournamentState) -> Vec<Bout> {
+    state
+        .arenas
+        .values()
+        .flat_map(|arena| {
+            arena
+                .played
+                .iter()
+                .chain(arena.current.iter())
+                .filter(|m| m.started_at.is_some())
+                .map(|m| Bout {
+                    arena: arena.name.clone(),
+                    result: m.clone(),
+                })
+        })
+        .collect()
+}
+
+/// A match as described in a referee event
+struct MatchDescription {
+    number: usize,
+    bots: Vec<String>,
+    limit: Option<Duration>,
+    is_waiting: bool,
+    is_ended: bool,
+    scores: BTreeMap<String, i64>,
+}
+
+impl MatchDescription {
+    /// Parses the text of `Match::describe`
+    fn decode(text: &str) -> Option<Self> {
+        let (number, rest) = text.strip_prefix("match ")?.split_once(": ")?;
+        let (rest, scores_text) = rest.rsplit_once(", score ")?;
+        let (bots, state) = rest.split_once(", ")?;
+        let bots: Vec<String> = bots.split(" vs ").map(str::to_string).collect();
+
+        let (state, limit) = match state.strip_suffix("s limit)") {
+            Some(state) => {
+                let (state, limit) = state.rsplit_once(" (")?;
+                (state, Some(Duration::from_secs(limit.parse().ok()?)))
+            }
+            None => (state, None),
+        };
+
+        // Bot names can have spaces, so the scores are split by the known names, in
+        // the order of the score map
+        let mut sorted_bots = bots.clone();
+        sorted_bots.sort();
+        let mut scores = BTreeMap::new();
+        let mut rest = scores_text;
+        for bot in sorted_bots {
+            let after_name = rest.strip_prefix(bot.as_str())?.strip_prefix(' ')?;
+            let (score, after_score) = after_name.split_once(' ').unwrap_or((after_name, ""));
+            scores.insert(bot, score.parse().ok()?);
+            rest = after_score;
+        }
+
+        Some(Self {
+            number: number.parse().ok()?,
+            bots,
+            limit,
+            is_waiting: state == "waiting",
+            is_ended: state.starts_with("ended after "),
+            scores,
+        })
+    }
+
+    /// Finds the match description in the message of a referee event
+    fn find(message: &str) -> Option<Self> {
+        if let Some(index) = message.find(":next match ") {
+            return Self::decode(&message[index + ":next ".len()..]);
+        }
+        let index = message.rfind(" (match ")?;
+        Self::decode(message[index + " (".len()..].strip_suffix(')')?)
+    }
+}
+
+/// Rebuilds the bouts from the referee events of a recording
+pub fn recording_bouts(events: &[RecordedEvent]) -> Vec<Bout> {
+    let referee = EventKind::Referee.encode();
+    let emergency_stop = format!(":operator:{} to all bots", RefereeCommand::Stop);
+    let mut current: BTreeMap<String, Bout> = BTreeMap::new();
+    let mut bouts = Vec::new();
+    for event in events.iter().filter(|event| event.kind == referee) {
+        // The operator stop, on the console or at shutdown, ends the running matches
+        if event.arena.is_none() && event.message.ends_with(&emergency_stop) {
+            for bout in current.values_mut() {
+                if bout.result.is_running() {
+                    bout.result.ended_at = Some(event.time);
+                }
+            }
+            continue;
+        }
+        let (arena, description) = match (&event.arena, MatchDescription::find(&event.message)) {
+            (Some(arena), Some(description)) => (arena, description),
+            _ => continue,
+        };
+
+        let is_same_match = current.get(arena).is_some_and(|bout| {
+            bout.result.number == description.number && bout.result.bots == description.bots
+        });
+        if !is_same_match {
+            // The broker only keeps the matches that started
+            if let Some(bout) = current.remove(arena) {
+                if bout.result.started_at.is_some() {
+                    bouts.push(bout);
+                }
+            }
+            current.insert(
+                arena.clone(),
+                Bout {
+                    arena: arena.clone(),
+                    result: Match::new(
+                        description.number,
+                        description.bots.clone(),
+                        description.limit,
+                    ),
+                },
+            );
+        }
+
+        let Some(bout) = current.get_mut(arena) else {
+            continue;
+        };
+        bout.result.scores = description.scores;
+        if !description.is_waiting && bout.result.started_at.is_none() {
+            bout.result.started_at = Some(event.time);
+        }
+        if description.is_ended && bout.result.ended_at.is_none() {
+            bout.result.ended_at = Some(event.time);
+        }
+    }
+    bouts.extend(
+        current
+            .into_values()
+            .filter(|bout| bout.result.started_at.is_some()),
+    );
+    bouts
+}
+
+/// How a bot did over all its bouts
+#[derive(Default)]
+pub struct Standing {
+    pub bot: String,
+    pub played: usize,
+    pub wins: usize,
+    pub draws: usize,
+    pub losses: usize,
+    pub points: i64,
+}
+
+/// Points and results of every bot, best first
+pub fn standings(bouts: &[Bout]) -> Vec<Standing> {
+    let mut standings: BTreeMap<&str, Standing> = BTreeMap::new();
+    for bout in bouts.iter() {
+        let winner = bout.winner();
+        for (bot, score) in bout.result.scores.iter() {
+            let standing = standings.entry(bot).or_insert_with(|| Standing {
+                bot: bot.clone(),
+                ..Default::default()
+            });
+            standing.points += score;
+            if !bout.is_finished() {
+                continue;
+            }
+            standing.played += 1;
+            match winner {
+                Some(winner) if winner == bot => standing.wins += 1,
+                Some(_) => standing.losses += 1,
+                None => standing.draws += 1,
+            }
+        }
+    }
+    let mut standings: Vec<Standing> = standings.into_values().collect();
+    standings.sort_by(|a, b| {
+        b.points
+            .cmp(&a.points)
+            .then(b.wins.cmp(&a.wins))
+            .then(a.bot.cmp(&b.bot))
+    });
+    standings
+}
+
+/// What to publish, and where
+pub struct ResultsOptions {
+    pub arenas: Vec<String>,
+    pub output: PathBuf,
+    pub title: String,
+}
+
+fn format_time(time: Option<DateTime<Local>>) -> String {
+    time.map(|time| time.format(TIME_FORMAT).to_string())
+        .unwrap_or_default()
+}
+
+fn format_duration(duration: Option<Duration>) -> String {
+    duration
+        .map(|duration| format!("{:.1}", duration.as_secs_f64()))
+        .unwrap_or_default()
+}
+
+fn format_scores(bout: &Bout) -> String {
+    bout.scores()
+        .iter()
+        .map(i64::to_string)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn write_bouts_csv(path: &Path, bouts: &[Bout]) -> Result<(), Box<dyn Error>> {
+    let mut text = String::from(
+        "arena,match,bots,scores,winner,started,ended,duration_seconds,limit_seconds\n",
+    );
+    for bout in bouts.iter() {
+        let winner = match (bout.is_finished(), bout.winner()) {
+            (false, _) => "unfinished",
+            (true, Some(winner)) => winner,
+            (true, None) => "draw",
+        };
+        text.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{}\n",
+            bout.arena,
+            bout.result.number,
+            bout.result.bots.join(" vs "),
+            format_scores(bout),
+            winner,
+            format_time(bout.result.started_at),
+            format_time(bout.result.ended_at),
+            format_duration(bout.duration()),
+            bout.result
+                .duration
+                .map(|limit| limit.as_secs().to_string())
+                .unwrap_or_default()
+        ));
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+fn write_standings_csv(path: &Path, standings: &[Standing]) -> Result<(), Box<dyn Error>> {
+    let mut text = String::from("rank,bot,played,wins,draws,losses,points\n");
+    for (rank, standing) in standings.iter().enumerate() {
+        text.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            rank + 1,
+            standing.bot,
+            standing.played,
+            standing.wins,
+            standing.draws,
+            standing.losses,
+            standing.points
+        ));
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+const HTML_STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; background: #fafafa; }
+h1 { margin-bottom: 0.2em; }
+.generated { color: #777; margin-top: 0; }
+table { border-collapse: collapse; margin: 1em 0 2em; background: #fff; }
+th, td { border: 1px solid #ddd; padding: 0.4em 0.8em; text-align: left; }
+th { background: #eee; }
+td.number { text-align: right; }
+.bracket { display: flex; flex-wrap: wrap; gap: 2em; margin-bottom: 2em; }
+.arena { display: flex; flex-direction: column; gap: 0.8em; min-width: 14em; }
+.arena h3 { margin: 0; }
+.bout { border: 1px solid #ccc; border-radius: 6px; background: #fff; padding: 0.5em 0.8em; }
+.bout .title { color: #777; font-size: 0.85em; }
+.bout .bot { display: flex; justify-content: space-between; gap: 1em; }
+.bout .winner { font-weight: bold; color: #1a7f37; }
+.bout .details { color: #777; font-size: 0.85em; }
+";
+
+fn render_html(title: &str, bouts: &[Bout], standings: &[Standing]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n\
+         <p class=\"generated\">Generated {}</p>\n",
+        escape_html(title),
+        HTML_STYLE,
+        escape_html(title),
+        Local::now().format(TIME_FORMAT)
+    );
+
+    html.push_str("<h2>Final standings</h2>\n<table>\n<tr><th>Rank</th><th>Bot</th><th>Played</th><th>Wins</th><th>Draws</th><th>Losses</th><th>Points</th></tr>\n");
+    for (rank, standing) in standings.iter().enumerate() {
+        html.push_str(&format!(
+            "<tr><td class=\"number\">{}</td><td>{}</td><td class=\"number\">{}</td>\
+             <td class=\"number\">{}</td><td class=\"number\">{}</td>\
+             <td class=\"number\">{}</td><td class=\"number\">{}</td></tr>\n",
+            rank + 1,
+            escape_html(&standing.bot),
+            standing.played,
+            standing.wins,
+            standing.draws,
+            standing.losses,
+            standing.points
+        ));
+    }
+    html.push_str("</table>\n");
+
+    let mut arenas: BTreeMap<&str, Vec<&Bout>> = BTreeMap::new();
+    for bout in bouts.iter() {
+        arenas.entry(&bout.arena).or_default().push(bout);
+    }
+    html.push_str("<h2>Bracket</h2>\n<div class=\"bracket\">\n");
+    for (arena, arena_bouts) in arenas.iter() {
+        html.push_str(&format!(
+            "<div class=\"arena\">\n<h3>{}</h3>\n",
+            escape_html(arena)
+        ));
+        for bout in arena_bouts.iter() {
+            let winner = bout.winner();
+            html.push_str(&format!(
+                "<div class=\"bout\">\n<div class=\"title\">Match {}</div>\n",
+                bout.result.number
+            ));
+            for (bot, score) in bout.result.bots.iter().zip(bout.scores()) {
+                let class = if winner == Some(bot.as_str()) {
+                    "bot winner"
+                } else {
+                    "bot"
+                };
+                html.push_str(&format!(
+                    "<div class=\"{}\"><span>{}</span><span>{}</span></div>\n",
+                    class,
+                    escape_html(bot),
+                    score
+                ));
+            }
+            let details = match (bout.duration(), bout.is_finished(), winner) {
+                (_, false, _) => "unfinished".to_string(),
+                (duration, true, None) => format!("draw, {}s", format_duration(duration)),
+                (duration, true, Some(_)) => format!("{}s", format_duration(duration)),
+            };
+            html.push_str(&format!(
+                "<div class=\"details\">{}</div>\n</div>\n",
+                details
+            ));
+        }
+        html.push_str("</div>\n");
+    }
+    html.push_str("</div>\n");
+
+    html.push_str("<h2>Bouts</h2>\n<table>\n<tr><th>Arena</th><th>Match</th><th>Bots</th><th>Score</th><th>Winner</th><th>Started</th><th>Duration (s)</th><th>Limit (s)</th></tr>\n");
+    for bout in bouts.iter() {
+        let winner = match (bout.is_finished(), bout.winner()) {
+            (false, _) => "unfinished".to_string(),
+            (true, Some(winner)) => escape_html(winner),
+            (true, None) => "draw".to_string(),
+        };
+        html.push_str(&format!(
+            "<tr><td>{}</td><td class=\"number\">{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td></tr>\n",
+            escape_html(&bout.arena),
+            bout.result.number,
+            escape_html(&bout.result.bots.join(" vs ")),
+            format_scores(bout),
+            winner,
+            format_time(bout.result.started_at),
+            format_duration(bout.duration()),
+            bout.result
+                .duration
+                .map(|limit| limit.as_secs().to_string())
+                .unwrap_or_default()
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Writes results.html, bouts.csv and standings.csv
+pub fn export(mut bouts: Vec<Bout>, options: &ResultsOptions) -> Result<(), Box<dyn Error>> {
+    bouts.retain(|bout| {
+        options.arenas.is_empty()
+            || options
+                .arenas
+                .iter()
+                .any(|pattern| name_matches(pattern, &bout.arena))
+    });
+    bouts.sort_by(|a, b| {
+        a.arena
+            .cmp(&b.arena)
+            .then(a.result.started_at.cmp(&b.result.started_at))
+    });
+    if bouts.is_empty() {
+        println!("no matches played");
+    }
+    let standings = standings(&bouts);
+
+    std::fs::create_dir_all(&options.output)?;
+    let html_path = options.output.join("results.html");
+    std::fs::write(&html_path, render_html(&options.title, &bouts, &standings))?;
+    let bouts_path = options.output.join("bouts.csv");
+    write_bouts_csv(&bouts_path, &bouts)?;
+    let standings_path = options.output.join("standings.csv");
+    write_standings_csv(&standings_path, &standings)?;
+    println!(
+        "{} bouts of {} bots written to {}, {} and {}",
+        bouts.len(),
+        standings.len(),
+        html_path.display(),
+        bouts_path.display(),
+        standings_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished_bout(scores: &[(&str, i64)]) -> Bout {
+        let bots = scores.iter().map(|(bot, _)| bot.to_string()).collect();
+        let mut result = Match::new(1, bots, None);
+        for (bot, score) in scores {
+            result.scores.insert(bot.to_string(), *score);
+        }
+        let now = Local::now();
+        result.started_at = Some(now);
+        result.ended_at = Some(now);
+        Bout {
+            arena: "north".to_string(),
+            result,
+        }
+    }
+
+    fn referee_event(time: DateTime<Local>, arena: Option<&str>, message: String) -> RecordedEvent {
+        RecordedEvent {
+            time,
+            kind: EventKind::Referee.encode().to_string(),
+            bot: None,
+            team: None,
+            arena: arena.map(str::to_string),
+            level: None,
+            message,
+        }
+    }
+
+    #[test]
+    fn match_descriptions_are_parsed() {
+        let description = MatchDescription::decode(
+            "match 2: big frog vs toad, running for 12s (60s limit), score big frog 1 toad -2",
+        )
+        .unwrap();
+        assert_eq!(description.number, 2);
+        assert_eq!(description.bots, vec!["big frog", "toad"]);
+        assert_eq!(description.limit, Some(Duration::from_secs(60)));
+        assert!(!description.is_waiting && !description.is_ended);
+        assert_eq!(description.scores["big frog"], 1);
+        assert_eq!(description.scores["toad"], -2);
+
+        let description =
+            MatchDescription::decode("match 3: frog vs toad, waiting, score frog 0 toad 0")
+                .unwrap();
+        assert!(description.is_waiting);
+        assert_eq!(description.limit, None);
+        assert!(MatchDescription::decode("match 3: frog vs toad, waiting").is_none());
+        assert!(MatchDescription::decode("match x: frog vs toad, waiting, score").is_none());
+    }
+
+    #[test]
+    fn match_descriptions_are_found_in_referee_messages() {
+        let description = MatchDescription::find(
+            "north:referee:next match 1: frog vs toad, waiting, score frog 0 toad 0",
+        )
+        .unwrap();
+        assert_eq!(description.number, 1);
+        let description = MatchDescription::find(
+            "north:time up, stop (match 1: frog vs toad, ended after 60s (60s limit), score frog 2 toad 1)",
+        )
+        .unwrap();
+        assert!(description.is_ended);
+        assert!(MatchDescription::find("north:referee:start").is_none());
+    }
+
+    #[test]
+    fn bouts_are_rebuilt_from_a_recording() {
+        let start = Local::now();
+        let mut m = Match::new(1, vec!["frog".to_string(), "toad".to_string()], None);
+        let mut events = vec![referee_event(
+            start,
+            Some("north"),
+            format!("north:referee:next {}", m.describe(start)),
+        )];
+        m.started_at = Some(start);
+        m.scores.insert("frog".to_string(), 2);
+        let later = start + chrono::Duration::seconds(30);
+        events.push(referee_event(
+            later,
+            Some("north"),
+            format!("north:referee:score ({})", m.describe(later)),
+        ));
+        // Matches that never started are left out
+        let waiting = Match::new(2, vec!["newt".to_string(), "toad".to_string()], None);
+        events.push(referee_event(
+            later,
+            Some("south"),
+            format!("south:referee:next {}", waiting.describe(later)),
+        ));
+        let end = later + chrono::Duration::seconds(5);
+        events.push(referee_event(
+            end,
+            None,
+            format!(":operator:{} to all bots", RefereeCommand::Stop),
+        ));
+
+        let bouts = recording_bouts(&events);
+        assert_eq!(bouts.len(), 1);
+        assert_eq!(bouts[0].arena, "north");
+        assert_eq!(bouts[0].result.started_at, Some(later));
+        assert_eq!(bouts[0].result.ended_at, Some(end));
+        assert_eq!(bouts[0].winner(), Some("frog"));
+    }
+
+    #[test]
+    fn standings_rank_points_then_wins() {
+        let bouts = vec![
+            finished_bout(&[("frog", 3), ("toad", 1)]),
+            finished_bout(&[("frog", 1), ("newt", 1)]),
+            finished_bout(&[("toad", 4), ("newt", 0)]),
+        ];
+        assert_eq!(bouts[1].winner(), None);
+        let standings = standings(&bouts);
+        let ranking: Vec<(&str, usize, usize, usize, i64)> = standings
+            .iter()
+            .map(|s| (s.bot.as_str(), s.wins, s.draws, s.losses, s.points))
+            .collect();
+        assert_eq!(
+            ranking,
+            vec![
+                ("toad", 1, 0, 1, 5),
+                ("frog", 1, 1, 0, 4),
+                ("newt", 0, 1, 1, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            escape_html("<b>\"frog\" & 'toad'</b>"),
+            "&lt;b&gt;&quot;frog&quot; &amp; &#39;toad&#39;&lt;/b&gt;"
+        );
+        let html = render_html("Cup <1>", &[finished_bout(&[("a&b", 1)])], &[]);
+        assert!(html.contains("<title>Cup &lt;1&gt;</title>"));
+        assert!(html.contains("a&amp;b"));
+        assert!(!html.contains("a&b"));
+    }
+}
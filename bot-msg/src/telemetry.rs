@@ -0,0 +1,523 @@
+//! Compact binary telemetry sent by bots alongside their text lines
+//!
+//! A frame starts with `TELEMETRY_MARKER`, a byte that never starts a text line,
+//! followed by the payload length as a little endian `u16` and by the payload:
+//!
+//! - bot time in milliseconds, `u32`
+//! - number of samples, `u8`
+//! - for each sample the signal id (`u8`), the value type (`u8`) and the value
+//!
+//! Value types are 0 for `u8`, 1 for `i16`, 2 for `u16`, 3 for `i32` and 4 for
+//! `f32`, all little endian. Bots name their signals with text lines like
+//! `SIGNAL:<id>:<name>[:<unit>]`, for instance `SIGNAL:3:motor.left:duty`.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{is_param_name_valid, name_matches, recording::RecordedEvent};
+
+pub const TELEMETRY_MARKER: u8 = 0x01;
+
+/// Marks bot telemetry sent to clients, followed by a `TelemetryLine` as JSON
+pub const TELEMETRY_PREFIX: &str = "TELEMETRY:";
+
+/// A sample value, a plain JSON number for clients
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum TelemetryValue {
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    F32(f32),
+}
+
+impl TelemetryValue {
+    fn decode(value_type: u8, bytes: &[u8]) -> Option<(Self, usize)> {
+        let value = match value_type {
+            0 => Self::U8(*bytes.first()?),
+            1 => Self::I16(i16::from_le_bytes(bytes.get(..2)?.try_into().ok()?)),
+            2 => Self::U16(u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?)),
+            3 => Self::I32(i32::from_le_bytes(bytes.get(..4)?.try_into().ok()?)),
+            4 => Self::F32(f32::from_le_bytes(bytes.get(..4)?.try_into().ok()?)),
+            _ => return None,
+        };
+        let size = match value {
+            Self::U8(_) => 1,
+            Self::I16(_) | Self::U16(_) => 2,
+            Self::I32(_) | Self::F32(_) => 4,
+        };
+        Some((value, size))
+    }
+}
+
+impl std::fmt::Display for TelemetryValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TelemetryValue::U8(v) => v.fmt(f),
+            TelemetryValue::I16(v) => v.fmt(f),
+            TelemetryValue::U16(v) => v.fmt(f),
+            TelemetryValue::I32(v) => v.fmt(f),
+            TelemetryValue::F32(v) => v.fmt(f),
+        }
+    }
+}
+
+/// The samples of a telemetry frame, as decoded from its payload
+#[derive(Clone, Debug)]
+pub struct TelemetryFrame {
+    pub bot_time_ms: u32,
+    pub samples: Vec<(u8, TelemetryValue)>,
+}
+
+impl TelemetryFrame {
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let bot_time_ms = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+        let count = *payload.get(4)? as usize;
+        let mut samples = Vec::with_capacity(count);
+        let mut rest = payload.get(5..)?;
+        for _ in 0..count {
+            let (signal, value_type) = (*rest.first()?, *rest.get(1)?);
+            let (value, size) = TelemetryValue::decode(value_type, rest.get(2..)?)?;
+            samples.push((signal, value));
+            rest = &rest[2 + size..];
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Self {
+            bot_time_ms,
+            samples,
+        })
+    }
+}
+
+/// A signal advertised by a bot for its telemetry frames
+#[derive(Clone, Debug)]
+pub struct TelemetrySignal {
+    pub id: u8,
+    pub name: String,
+    pub unit: Option<String>,
+}
+
+impl TelemetrySignal {
+    pub fn decode(text: &str) -> Option<Self> {
+        let mut parts = text.split(':');
+        let id = parts.next()?.parse().ok()?;
+        let name = parts.next()?;
+        if !is_param_name_valid(name) {
+            return None;
+        }
+        let unit = parts.next().filter(|u| !u.is_empty()).map(str::to_string);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            id,
+            name: name.to_string(),
+            unit,
+        })
+    }
+}
+
+impl std::fmt::Display for TelemetrySignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("signal {} {}", self.id, self.name))?;
+        if let Some(unit) = &self.unit {
+            f.write_fmt(format_args!(" ({})", unit))?;
+        }
+        Ok(())
+    }
+}
+
+/// A sample of a telemetry frame, with its signal named by the bot, or by id as `s<id>`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TelemetrySample {
+    pub signal: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    pub value: TelemetryValue,
+}
+
+/// A telemetry frame as sent to clients, with the signals already named
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TelemetryLine {
+    pub bot: String,
+    pub bot_time_ms: u32,
+    pub samples: Vec<TelemetrySample>,
+}
+
+impl TelemetryLine {
+    pub fn new(bot: &str, frame: &TelemetryFrame, signals: &BTreeMap<u8, TelemetrySignal>) -> Self {
+        let samples = frame
+            .samples
+            .iter()
+            .map(|(id, value)| TelemetrySample {
+                signal: signals
+                    .get(id)
+                    .map(|signal| signal.name.clone())
+                    .unwrap_or_else(|| format!("s{}", id)),
+                unit: signals.get(id).and_then(|signal| signal.unit.clone()),
+                value: *value,
+            })
+            .collect();
+        Self {
+            bot: bot.to_string(),
+            bot_time_ms: frame.bot_time_ms,
+            samples,
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, serde_json::Error> {
+        Ok(format!(
+            "{}{}",
+            TELEMETRY_PREFIX,
+            serde_json::to_string(self)?
+        ))
+    }
+
+    pub fn decode(line: &str) -> Option<Self> {
+        serde_json::from_str(line.strip_prefix(TELEMETRY_PREFIX)?.trim_end()).ok()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 2] = [ExportFormat::Csv, ExportFormat::Json];
+
+    pub fn encode(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.encode() == text)
+    }
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s).ok_or_else(|| format!("invalid export format '{}'", s))
+    }
+}
+
+/// Which telemetry gets exported, and where
+pub struct ExportOptions {
+    pub bots: Vec<String>,
+    pub from: Option<DateTime<Local>>,
+    pub to: Option<DateTime<Local>>,
+    pub format: ExportFormat,
+    pub output: PathBuf,
+}
+
+/// The samples of one bot, one row per frame and one column per signal
+#[derive(Default)]
+struct BotTrace {
+    times: Vec<DateTime<Local>>,
+    bot_times_ms: Vec<u32>,
+    signals: BTreeMap<String, Vec<Option<String>>>,
+}
+
+impl BotTrace {
+    fn push(&mut self, time: DateTime<Local>, line: TelemetryLine) {
+        let row = self.times.len();
+        self.times.push(time);
+        self.bot_times_ms.push(line.bot_time_ms);
+        for sample in line.samples {
+            let column = self.signals.entry(sample.signal).or_default();
+            column.resize(row, None);
+            column.push(Some(sample.value.to_string()));
+        }
+        for column in self.signals.values_mut() {
+            column.resize(row + 1, None);
+        }
+    }
+
+    fn seconds(&self, row: usize, start: DateTime<Local>) -> f64 {
+        (self.times[row] - start).num_milliseconds() as f64 / 1000.0
+    }
+
+    fn write_csv(&self, path: &Path, start: DateTime<Local>) -> Result<(), Box<dyn Error>> {
+        let mut text = String::from("time,seconds,bot_time_ms");
+        for name in self.signals.keys() {
+            text.push(',');
+            text.push_str(name);
+        }
+        text.push('\n');
+        for row in 0..self.times.len() {
+            text.push_str(&format!(
+                "{},{:.3},{}",
+                self.times[row].to_rfc3339(),
+                self.seconds(row, start),
+                self.bot_times_ms[row]
+            ));
+            for column in self.signals.values() {
+                text.push(',');
+                text.push_str(column[row].as_deref().unwrap_or_default());
+            }
+            text.push('\n');
+        }
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    fn write_json(
+        &self,
+        path: &Path,
+        bot: &str,
+        start: DateTime<Local>,
+    ) -> Result<(), Box<dyn Error>> {
+        let signals: serde_json::Map<String, Value> = self
+            .signals
+            .iter()
+            .map(|(name, column)| {
+                let values = column
+                    .iter()
+                    .map(|value| match value {
+                        Some(value) => value
+                            .parse::<f64>()
+                            .ok()
+                            .and_then(|value| {
+                                serde_json::Number::from_f64(value).map(Value::Number)
+                            })
+                            .unwrap_or_else(|| Value::String(value.clone())),
+                        None => Value::Null,
+                    })
+                    .collect();
+                (name.clone(), Value::Array(values))
+            })
+            .collect();
+        let columns = json!({
+            "bot": bot,
+            "time": self.times.iter().map(DateTime::to_rfc3339).collect::<Vec<_>>(),
+            "seconds": (0..self.times.len()).map(|row| self.seconds(row, start)).collect::<Vec<_>>(),
+            "bot_time_ms": self.bot_times_ms,
+            "signals": signals,
+        });
+        std::fs::write(path, serde_json::to_string(&columns)?)?;
+        Ok(())
+    }
+}
+
+/// Writes the telemetry found in recorded events to one file per bot
+///
+/// The `seconds` column counts from the start of the time window, or from the
+/// first exported sample when the window has no start.
+pub fn export(events: &[RecordedEvent], options: &ExportOptions) -> Result<(), Box<dyn Error>> {
+    let mut traces: BTreeMap<String, BotTrace> = BTreeMap::new();
+    for event in events.iter() {
+        let is_in_window = options.from.map(|from| event.time >= from).unwrap_or(true)
+            && options.to.map(|to| event.time <= to).unwrap_or(true);
+        if !is_in_window {
+            continue;
+        }
+        let line = match TelemetryLine::decode(&event.message) {
+            Some(line) => line,
+            None => continue,
+        };
+        let is_bot_selected = options.bots.is_empty()
+            || options
+                .bots
+                .iter()
+                .any(|pattern| name_matches(pattern, &line.bot));
+        if is_bot_selected {
+            traces
+                .entry(line.bot.clone())
+                .or_default()
+                .push(event.time, line);
+        }
+    }
+
+    if traces.is_empty() {
+        println!("no telemetry found");
+    }
+    std::fs::create_dir_all(&options.output)?;
+    for (bot, trace) in traces.iter() {
+        let start = match options.from.or(trace.times.first().copied()) {
+            Some(start) => start,
+            None => continue,
+        };
+        // Bot names can have dots, which `with_extension` would take for an extension
+        let path = options
+            .output
+            .join(format!("{}.{}", bot, options.format.encode()));
+        match options.format {
+            ExportFormat::Csv => trace.write_csv(&path, start)?,
+            ExportFormat::Json => trace.write_json(&path, bot, start)?,
+        }
+        println!(
+            "{}: {} frames of {} signals written to {}",
+            bot,
+            trace.times.len(),
+            trace.signals.len(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(bot_time_ms: u32, samples: &[(u8, u8, &[u8])]) -> Vec<u8> {
+        let mut payload = bot_time_ms.to_le_bytes().to_vec();
+        payload.push(samples.len() as u8);
+        for (signal, value_type, value) in samples {
+            payload.extend([*signal, *value_type]);
+            payload.extend(*value);
+        }
+        payload
+    }
+
+    #[test]
+    fn frames_decode_every_value_type() {
+        let payload = payload(
+            1234,
+            &[
+                (1, 0, &[200]),
+                (2, 1, &(-300i16).to_le_bytes()),
+                (3, 2, &60000u16.to_le_bytes()),
+                (4, 3, &(-70000i32).to_le_bytes()),
+                (5, 4, &0.5f32.to_le_bytes()),
+            ],
+        );
+        let frame = TelemetryFrame::decode(&payload).unwrap();
+        assert_eq!(frame.bot_time_ms, 1234);
+        assert_eq!(
+            frame.samples,
+            vec![
+                (1, TelemetryValue::U8(200)),
+                (2, TelemetryValue::I16(-300)),
+                (3, TelemetryValue::U16(60000)),
+                (4, TelemetryValue::I32(-70000)),
+                (5, TelemetryValue::F32(0.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_with_bad_lengths_or_types_are_rejected() {
+        let good = payload(1, &[(1, 1, &[1, 0])]);
+        assert!(TelemetryFrame::decode(&good).is_some());
+        assert!(TelemetryFrame::decode(&good[..good.len() - 1]).is_none());
+        assert!(TelemetryFrame::decode(&[good.as_slice(), &[0]].concat()).is_none());
+        assert!(TelemetryFrame::decode(&payload(1, &[(1, 9, &[1])])).is_none());
+        assert!(TelemetryFrame::decode(&[1, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn signals_decode_with_optional_unit() {
+        let signal = TelemetrySignal::decode("3:motor.left:duty").unwrap();
+        assert_eq!((signal.id, signal.name.as_str()), (3, "motor.left"));
+        assert_eq!(signal.unit.as_deref(), Some("duty"));
+        assert_eq!(TelemetrySignal::decode("4:speed").unwrap().unit, None);
+        assert!(TelemetrySignal::decode("300:speed").is_none());
+        assert!(TelemetrySignal::decode("4:bad name").is_none());
+        assert!(TelemetrySignal::decode("4:speed:m/s:extra").is_none());
+    }
+
+    #[test]
+    fn lines_round_trip_for_bots_named_by_address() {
+        let signals: BTreeMap<u8, TelemetrySignal> =
+            [(3, TelemetrySignal::decode("3:motor.left:duty").unwrap())].into();
+        let frame = TelemetryFrame::decode(&payload(
+            99,
+            &[
+                (3, 4, &0.25f32.to_le_bytes()),
+                (7, 1, &(-12i16).to_le_bytes()),
+            ],
+        ))
+        .unwrap();
+        for bot in ["frog", "10.0.0.5:4000", "[fe80::1]:4000"] {
+            let line = TelemetryLine::new(bot, &frame, &signals);
+            let decoded = TelemetryLine::decode(&line.encode().unwrap()).unwrap();
+            assert_eq!(decoded.bot, bot);
+            assert_eq!(decoded.bot_time_ms, 99);
+            assert_eq!(decoded.samples[0].signal, "motor.left");
+            assert_eq!(decoded.samples[0].unit.as_deref(), Some("duty"));
+            assert_eq!(decoded.samples[1].signal, "s7");
+            assert_eq!(decoded.samples[1].value.to_string(), "-12");
+        }
+        assert!(TelemetryLine::decode("TELEMETRY:frog:1:a=1").is_none());
+    }
+
+    fn telemetry_event(time: &str, bot: &str, samples: &[(&str, f32)]) -> RecordedEvent {
+        let line = TelemetryLine {
+            bot: bot.to_string(),
+            bot_time_ms: 0,
+            samples: samples
+                .iter()
+                .map(|(signal, value)| TelemetrySample {
+                    signal: signal.to_string(),
+                    unit: None,
+                    value: TelemetryValue::F32(*value),
+                })
+                .collect(),
+        };
+        RecordedEvent {
+            time: DateTime::parse_from_rfc3339(time).unwrap().into(),
+            kind: "telemetry".to_string(),
+            bot: Some(bot.to_string()),
+            team: None,
+            arena: None,
+            level: None,
+            message: line.encode().unwrap(),
+        }
+    }
+
+    #[test]
+    fn export_writes_one_file_per_bot_with_gaps_left_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("telemetry");
+        let events = vec![
+            telemetry_event("2024-05-01T14:00:00+00:00", "frog.v1", &[("speed", 1.0)]),
+            telemetry_event("2024-05-01T14:00:01+00:00", "frog.v2", &[("speed", 2.0)]),
+            telemetry_event("2024-05-01T14:00:02+00:00", "frog.v1", &[("turn", 0.5)]),
+            telemetry_event("2024-05-01T14:00:03+00:00", "toad", &[("speed", 3.0)]),
+        ];
+        let options = ExportOptions {
+            bots: vec!["frog*".to_string()],
+            from: None,
+            to: None,
+            format: ExportFormat::Csv,
+            output: output.clone(),
+        };
+        export(&events, &options).unwrap();
+
+        let v1 = std::fs::read_to_string(output.join("frog.v1.csv")).unwrap();
+        let v2 = std::fs::read_to_string(output.join("frog.v2.csv")).unwrap();
+        let toad_exists = output.join("toad.csv").exists();
+
+        let rows: Vec<Vec<&str>> = v1
+            .lines()
+            .map(|line| line.split(',').skip(1).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["seconds", "bot_time_ms", "speed", "turn"],
+                vec!["0.000", "0", "1", ""],
+                vec!["2.000", "0", "", "0.5"],
+            ]
+        );
+        assert_eq!(v2.lines().count(), 2);
+        assert!(!toad_exists);
+    }
+}